@@ -1,12 +1,15 @@
 //! Codegen utils.
 
+use std::collections::HashMap;
+
 use inkwell::{
     context::Context,
     memory_buffer::MemoryBuffer,
+    passes::PassManager,
     targets::{CodeModel, FileType, InitializationConfig, RelocMode, Target, TargetTriple},
-    types::FunctionType,
+    types::{FunctionType, PointerType},
     values::CallableValue,
-    values::{AnyValue, AnyValueEnum},
+    values::{AnyValue, AnyValueEnum, GlobalValue, IntValue, PointerValue},
     AddressSpace, OptimizationLevel,
 };
 use petgraph::{algo::toposort, Direction};
@@ -30,9 +33,29 @@ impl From<FormulaError> for CodegenError {
     }
 }
 
+// FIXME: use consts from redbpf
+pub const BPF_MAP_TYPE_HASH: u64 = 1;
+pub const BPF_MAP_TYPE_ARRAY: u64 = 2;
+pub const BPF_MAP_TYPE_PERCPU_HASH: u64 = 5;
+pub const BPF_MAP_TYPE_PERCPU_ARRAY: u64 = 6;
+pub const BPF_MAP_TYPE_RINGBUF: u64 = 27;
+
+/// Flag for `bpf_map_update_elem`: create a new element or update an existing one.
+pub const BPF_ANY: u64 = 0;
+/// Flag for `bpf_map_update_elem`: create a new element only if it doesn't exist.
+pub const BPF_NOEXIST: u64 = 1;
+
+/// A BPF hash map that needs to be populated with set values after the program is loaded.
+#[derive(Debug, Clone)]
+pub struct SetMap {
+    pub name: String,
+    pub values: Vec<u64>,
+}
+
 pub struct CodegenResult {
     pub bpf: Vec<u8>,
     pub asm: String,
+    pub set_maps: Vec<SetMap>,
 }
 
 /// Generates BPF code out of a Metalens program graph.
@@ -49,7 +72,25 @@ pub fn generate(prog: &ProgGraph) -> Result<CodegenResult, CodegenError> {
     // TODO: introduce a "probe" abstraction instead of functions.
     // this should create a new basic block etc.
 
-    let func = module.add_function("bpf", llvm_context.i32_type().fn_type(&[], false), None);
+    // the program receives a pointer to the probe context, and the index of the probed function
+    // if it's called by the per-function programs of a `UProbe`
+    let ctx_ptr_ty = llvm_context.i8_type().ptr_type(AddressSpace::Generic);
+    let func = module.add_function(
+        "bpf",
+        llvm_context
+            .i32_type()
+            .fn_type(&[ctx_ptr_ty.into(), llvm_context.i64_type().into()], false),
+        None,
+    );
+    let bpf_ctx = func
+        .get_first_param()
+        .expect("expected a context parameter")
+        .into_pointer_value();
+    bpf_ctx.set_name("ctx");
+    func.get_nth_param(1)
+        .expect("expected a function index parameter")
+        .set_name("function_index");
+
     let allocs_block = llvm_context.append_basic_block(func, "allocs");
     let entry_block = llvm_context.append_basic_block(func, "entry");
     let func_exit = llvm_context.append_basic_block(func, "exit");
@@ -63,11 +104,18 @@ pub fn generate(prog: &ProgGraph) -> Result<CodegenResult, CodegenError> {
         module,
         builder,
         func,
+        bpf_ctx,
         func_exit,
         allocs_block,
         current_block: entry_block,
+        set_maps: Vec::new(),
+        node_values: HashMap::new(),
+        stack_reads_len: 0,
     };
 
+    // probe nodes can change the program type during codegen
+    context.func.set_section("uprobe/mlens");
+
     while let Some(next_node_idx) = exec_order.next() {
         let next_node = &prog[next_node_idx];
 
@@ -92,6 +140,14 @@ pub fn generate(prog: &ProgGraph) -> Result<CodegenResult, CodegenError> {
         .builder
         .build_return(Some(&context.llvm_context.i32_type().const_zero()));
 
+    // inline programs called by other programs, see `UProbe::codegen`.
+    // the function index becomes a constant, so the branches on it are folded
+    let pass_manager = PassManager::create(());
+    pass_manager.add_always_inliner_pass();
+    pass_manager.add_global_dce_pass();
+    pass_manager.add_cfg_simplification_pass();
+    pass_manager.run_on(&context.module);
+
     // Create a BPF target and compile the module we have.
     Target::initialize_bpf(&InitializationConfig::default());
 
@@ -113,8 +169,6 @@ pub fn generate(prog: &ProgGraph) -> Result<CodegenResult, CodegenError> {
         .set_data_layout(&target_machine.get_target_data().get_data_layout());
 
     // add metainformation for the module
-    context.func.set_section("uprobe/mlens");
-
     let char_ty = context.llvm_context.i8_type();
     let chars = b"GPL\x00"
         .iter()
@@ -147,7 +201,11 @@ pub fn generate(prog: &ProgGraph) -> Result<CodegenResult, CodegenError> {
 
     let bpf = mem_buffer_obj.as_slice().to_vec();
 
-    Ok(CodegenResult { asm, bpf })
+    Ok(CodegenResult {
+        asm,
+        bpf,
+        set_maps: context.set_maps,
+    })
 }
 
 fn debug_sections(mem_buffer_obj: MemoryBuffer) {
@@ -174,6 +232,18 @@ pub fn generate_string_literal<'a>(ctx: &mut CodegenCtx<'a>, str: &str) -> AnyVa
     str_const_literal.as_any_value_enum()
 }
 
+/// Returns the type of a loaded BPF program, which receives a pointer to the probe context.
+pub fn bpf_prog_type<'a>(ctx: &CodegenCtx<'a>) -> FunctionType<'a> {
+    ctx.llvm_context.i32_type().fn_type(
+        &[ctx
+            .llvm_context
+            .i8_type()
+            .ptr_type(AddressSpace::Generic)
+            .into()],
+        false,
+    )
+}
+
 /// Helper to generate a function descriptor.
 pub fn gen_bpf_helper<'a>(
     ctx: &mut CodegenCtx<'a>,
@@ -190,6 +260,210 @@ pub fn gen_bpf_helper<'a>(
         .expect("could not create a callable value from function ptr")
 }
 
+/// Generates a call to a BPF helper that takes no arguments and returns a 64-bit value,
+/// e.g. bpf_ktime_get_ns.
+pub fn call_bpf_helper<'a>(ctx: &mut CodegenCtx<'a>, helper_num: u32, name: &str) -> IntValue<'a> {
+    let helper = gen_bpf_helper(
+        ctx,
+        helper_num,
+        ctx.llvm_context.i64_type().fn_type(&[], false),
+    );
+
+    ctx.builder
+        .build_call(helper, &[], name)
+        .as_any_value_enum()
+        .into_int_value()
+}
+
+/// Defines a BPF map in the `maps/<name>` section.
+pub fn generate_bpf_map<'a>(
+    ctx: &mut CodegenCtx<'a>,
+    name: &str,
+    map_type: u64,
+    key_size: u32,
+    value_size: u32,
+    max_entries: u32,
+) -> GlobalValue<'a> {
+    let i32_ty = ctx.llvm_context.i32_type();
+    let bpf_map_def = ctx.llvm_context.struct_type(
+        &[
+            i32_ty.into(), // type
+            i32_ty.into(), // key_size
+            i32_ty.into(), // value_size
+            i32_ty.into(), // max_entries
+            i32_ty.into(), // map_flags
+            i32_ty.into(), // inner_map_idx
+            i32_ty.into(), // numa_mode
+        ],
+        false,
+    );
+
+    let map = ctx
+        .module
+        .add_global(bpf_map_def, Some(AddressSpace::Global), name);
+
+    map.set_initializer(&bpf_map_def.const_named_struct(&[
+        i32_ty.const_int(map_type, false).into(),
+        i32_ty.const_int(key_size as u64, false).into(),
+        i32_ty.const_int(value_size as u64, false).into(),
+        i32_ty.const_int(max_entries as u64, false).into(),
+        i32_ty.const_zero().into(),
+        i32_ty.const_zero().into(),
+        i32_ty.const_zero().into(),
+    ]));
+
+    map.set_section(&format!("maps/{}", name));
+    map
+}
+
+/// Generates a call to bpf_map_lookup_elem.
+/// Returns a pointer to the map value, which is null if the key has not been found.
+pub fn bpf_map_lookup_elem<'a>(
+    ctx: &mut CodegenCtx<'a>,
+    map: PointerValue<'a>,
+    key: PointerValue<'a>,
+    value_ptr_ty: PointerType<'a>,
+) -> PointerValue<'a> {
+    let i8_ptr_ty = ctx.llvm_context.i8_type().ptr_type(AddressSpace::Generic);
+
+    let bpf_map_lookup_elem = gen_bpf_helper(
+        ctx,
+        1,
+        value_ptr_ty.fn_type(
+            &[
+                i8_ptr_ty.into(), // map
+                i8_ptr_ty.into(), // key
+            ],
+            false,
+        ),
+    );
+
+    let map = ctx.builder.build_pointer_cast(map, i8_ptr_ty, "map");
+    let key = ctx.builder.build_pointer_cast(key, i8_ptr_ty, "key");
+
+    ctx.builder
+        .build_call(
+            bpf_map_lookup_elem,
+            &[map.into(), key.into()],
+            "map_lookup_result",
+        )
+        .as_any_value_enum()
+        .into_pointer_value()
+}
+
+/// Generates a call to bpf_map_update_elem.
+/// Returns 0 on success or a negative error code.
+pub fn bpf_map_update_elem<'a>(
+    ctx: &mut CodegenCtx<'a>,
+    map: PointerValue<'a>,
+    key: PointerValue<'a>,
+    value: PointerValue<'a>,
+    flags: u64,
+) -> IntValue<'a> {
+    let i8_ptr_ty = ctx.llvm_context.i8_type().ptr_type(AddressSpace::Generic);
+    let i64_ty = ctx.llvm_context.i64_type();
+
+    let bpf_map_update_elem = gen_bpf_helper(
+        ctx,
+        2,
+        i64_ty.fn_type(
+            &[
+                i8_ptr_ty.into(), // map
+                i8_ptr_ty.into(), // key
+                i8_ptr_ty.into(), // value
+                i64_ty.into(),    // flags
+            ],
+            false,
+        ),
+    );
+
+    let map = ctx.builder.build_pointer_cast(map, i8_ptr_ty, "map");
+    let key = ctx.builder.build_pointer_cast(key, i8_ptr_ty, "key");
+    let value = ctx.builder.build_pointer_cast(value, i8_ptr_ty, "value");
+
+    ctx.builder
+        .build_call(
+            bpf_map_update_elem,
+            &[
+                map.into(),
+                key.into(),
+                value.into(),
+                i64_ty.const_int(flags, false).into(),
+            ],
+            "map_update_result",
+        )
+        .as_any_value_enum()
+        .into_int_value()
+}
+
+/// Generates a call to bpf_map_delete_elem.
+/// Returns 0 on success or a negative error code.
+pub fn bpf_map_delete_elem<'a>(
+    ctx: &mut CodegenCtx<'a>,
+    map: PointerValue<'a>,
+    key: PointerValue<'a>,
+) -> IntValue<'a> {
+    let i8_ptr_ty = ctx.llvm_context.i8_type().ptr_type(AddressSpace::Generic);
+
+    let bpf_map_delete_elem = gen_bpf_helper(
+        ctx,
+        3,
+        ctx.llvm_context.i64_type().fn_type(
+            &[
+                i8_ptr_ty.into(), // map
+                i8_ptr_ty.into(), // key
+            ],
+            false,
+        ),
+    );
+
+    let map = ctx.builder.build_pointer_cast(map, i8_ptr_ty, "map");
+    let key = ctx.builder.build_pointer_cast(key, i8_ptr_ty, "key");
+
+    ctx.builder
+        .build_call(
+            bpf_map_delete_elem,
+            &[map.into(), key.into()],
+            "map_delete_result",
+        )
+        .as_any_value_enum()
+        .into_int_value()
+}
+
+/// Looks up a value in a BPF array. Exits the program if the lookup fails.
+pub fn generate_map_lookup<'a>(
+    ctx: &mut CodegenCtx<'a>,
+    map: PointerValue<'a>,
+    index: IntValue<'a>,
+    value_ptr_ty: PointerType<'a>,
+) -> PointerValue<'a> {
+    let i32_ty = ctx.llvm_context.i32_type();
+
+    ctx.builder.position_at_end(ctx.allocs_block);
+    let key = ctx.builder.build_alloca(i32_ty, "key");
+
+    ctx.builder.position_at_end(ctx.current_block);
+    ctx.builder.build_store(key, index);
+
+    let value_ptr = bpf_map_lookup_elem(ctx, map, key, value_ptr_ty);
+    generate_null_check(ctx, value_ptr);
+    value_ptr
+}
+
+/// Exits the program if a map lookup result is null.
+pub fn generate_null_check<'a>(ctx: &mut CodegenCtx<'a>, value_ptr: PointerValue<'a>) {
+    // if (value_ptr == null) return;
+    let is_not_null = ctx.builder.build_is_not_null(value_ptr, "is_not_null");
+
+    let then_block = ctx
+        .llvm_context
+        .append_basic_block(ctx.func, "map_lookup_success");
+    ctx.builder
+        .build_conditional_branch(is_not_null, then_block, ctx.func_exit);
+
+    ctx.set_current_block(then_block);
+}
+
 /// Generates a call to bpf_printk.
 pub fn bpf_printk<'a>(ctx: &mut CodegenCtx<'a>, strk: &[u8]) {
     let bpf_printk = gen_bpf_helper(
@@ -2,14 +2,14 @@
 //! Think of it as an AST, used solely for representing the language structure.
 //! The actual state representation is built in the compiler.
 
-use petgraph::graph::NodeIndex;
+use petgraph::{algo::toposort, graph::NodeIndex, Direction};
 use serde::{Deserialize, Serialize};
 
 use std::collections::HashMap;
 
 use crate::{
-    formulas::{parse_formula, FormulaError},
-    nodes::{FilterNode, LabelNode, Node, NodeProperties, UProbe},
+    formulas::{parse_formula, Diagnostic, FormulaError, Span},
+    nodes::{FilterNode, KProbe, LabelNode, Latency, Node, NodeProperties, Tracepoint, UProbe},
     ProgGraph,
 };
 
@@ -19,11 +19,15 @@ pub fn construct_prog(nodes: Vec<Elem>) -> Result<ProgGraph, FormulaError> {
     let mut prog = ProgGraph::new();
 
     let mut node_ids: HashMap<NodeId, NodeIndex<u32>> = HashMap::new();
+    let mut source_ids = Vec::new();
 
     for node_desc in &nodes {
         let node = node_desc.construct_node()?;
 
         let node_id = node_desc.node_id().unwrap();
+        if node.is_source() {
+            source_ids.push(node_id);
+        }
         let node_idx = prog.add_node(node);
 
         // assoc dsl node_id with the graph node index
@@ -39,9 +43,47 @@ pub fn construct_prog(nodes: Vec<Elem>) -> Result<ProgGraph, FormulaError> {
         }
     }
 
+    typecheck_prog(&prog, &source_ids)?;
+
     Ok(prog)
 }
 
+/// Checks formula types for all nodes in the execution order.
+/// All source nodes share a single BPF program, so only one of them is allowed.
+fn typecheck_prog(prog: &ProgGraph, source_ids: &[NodeId]) -> Result<(), FormulaError> {
+    let exec_order = toposort(prog, None)
+        .map_err(|_| FormulaError::Other("cycles in the program graph".to_string()))?;
+
+    let mut errors = source_ids
+        .iter()
+        .skip(1)
+        .map(|&node_id| {
+            Diagnostic::new(
+                node_id,
+                "",
+                Span::default(),
+                "a program can only have one probe node".to_string(),
+            )
+            .with_hint("remove other probe nodes or run them as separate programs")
+        })
+        .collect::<Vec<_>>();
+
+    for node_idx in exec_order {
+        let inputs = prog
+            .neighbors_directed(node_idx, Direction::Incoming)
+            .map(|input_idx| &prog[input_idx])
+            .collect::<Vec<_>>();
+
+        errors.append(&mut prog[node_idx].typecheck(&inputs));
+    }
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(FormulaError::Diagnostics(errors))
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(tag = "@type")]
 pub enum Elem {
@@ -70,16 +112,26 @@ impl Elem {
         }
     }
 
+    /// Parses node property formulas, collecting errors from all properties.
     fn construct_properties(
+        node_id: NodeId,
         props: &HashMap<String, String>,
     ) -> Result<NodeProperties, FormulaError> {
         let mut node_props = NodeProperties::new();
+        let mut diagnostics = Vec::new();
 
         for (key, val) in props.iter() {
-            node_props.insert(key, parse_formula(val.as_str())?);
+            match parse_formula(val.as_str()) {
+                Ok(formula) => node_props.insert(key, formula),
+                Err(e) => diagnostics.append(&mut e.into_diagnostics(node_id, key, val)),
+            }
         }
 
-        Ok(node_props)
+        if diagnostics.is_empty() {
+            Ok(node_props)
+        } else {
+            Err(FormulaError::Diagnostics(diagnostics))
+        }
     }
 
     pub fn construct_node(&self) -> Result<Node, FormulaError> {
@@ -90,10 +142,15 @@ impl Elem {
             ..
         } = self;
 
-        let properties = Self::construct_properties(properties)?;
+        let properties = Self::construct_properties(*id, properties)?;
 
         let node = match node_type.as_str() {
             "UProbe" => Node::UProbe(UProbe::new(*id, properties)),
+            "URetProbe" => Node::UProbe(UProbe::new_return(*id, properties)),
+            "Latency" => Node::Latency(Latency::new(*id, properties)),
+            "KProbe" => Node::KProbe(KProbe::new(*id, properties)),
+            "KRetProbe" => Node::KProbe(KProbe::new_return(*id, properties)),
+            "Tracepoint" => Node::Tracepoint(Tracepoint::new(*id, properties)),
             "Filter" => Node::Filter(FilterNode::new(*id, properties)),
             "Label" => Node::Label(LabelNode::new(*id, properties)),
             _ => {
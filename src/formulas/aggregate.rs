@@ -0,0 +1,507 @@
+//! Aggregate functions.
+//!
+//! Aggregates accumulate values of a stream in a BPF map and can be used only as a node value,
+//! e.g. `sum(input.size)`.
+
+use serde::Serialize;
+
+use super::{eval, ExprType, FormulaExpr, FormulaTerm, Value};
+
+/// Number of buckets in a log2 histogram: one for values below 1 and one for each power of 2.
+pub const LOG2_BUCKETS: u32 = 65;
+
+/// Maximum number of buckets in a linear histogram.
+const MAX_LINEAR_BUCKETS: u64 = 1024;
+
+/// Log-linear histograms split each power of 2 into `2^LOG_LINEAR_SUB_BITS` linear buckets,
+/// which bounds the relative error of a percentile estimate by 1/32.
+pub const LOG_LINEAR_SUB_BITS: u32 = 4;
+
+/// Number of buckets in a log-linear histogram.
+pub const LOG_LINEAR_BUCKETS: u32 = (65 - LOG_LINEAR_SUB_BITS) << LOG_LINEAR_SUB_BITS;
+
+/// Maximum length of a string group key; longer strings are truncated.
+pub const MAX_GROUP_KEY_LEN: u32 = 64;
+
+/// Maximum number of groups of a grouped aggregate. Values with new keys are dropped
+/// once the limit is reached.
+pub const MAX_GROUPS: u32 = 10240;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AggregateFunc {
+    Count,
+    Sum,
+    Min,
+    Max,
+    Avg,
+    /// Log2 histogram.
+    Hist,
+    /// Linear histogram.
+    LHist(LinearBuckets),
+    /// Percentile estimated from a log-linear histogram.
+    /// The rank is in hundredths of a percent, e.g. 9900 for p99.
+    Percentile(u32),
+}
+
+impl AggregateFunc {
+    /// Returns true if `name` is an aggregate function that takes a value argument.
+    /// `count` is not included as it's a separate grammar rule.
+    pub fn is_aggregate(name: &str) -> bool {
+        matches!(
+            name,
+            "sum" | "min" | "max" | "avg" | "hist" | "lhist" | "percentile"
+        )
+    }
+
+    /// Constructs an aggregate function from a call.
+    /// Returns an error message if the arguments are invalid; the aggregated value is not checked.
+    pub fn from_call(name: &str, args: &[FormulaExpr]) -> Result<Self, String> {
+        let arity = match name {
+            "lhist" => 4,
+            "percentile" => 2,
+            _ => 1,
+        };
+        if args.len() != arity {
+            return Err(format!(
+                "`{}` takes {} argument(s), found {}",
+                name,
+                arity,
+                args.len()
+            ));
+        }
+
+        Ok(match name {
+            "sum" => AggregateFunc::Sum,
+            "min" => AggregateFunc::Min,
+            "max" => AggregateFunc::Max,
+            "avg" => AggregateFunc::Avg,
+            "hist" => AggregateFunc::Hist,
+            "lhist" => {
+                // bucket bounds must be known at compile time
+                let params = args[1..]
+                    .iter()
+                    .map(|arg| match eval(arg) {
+                        Ok(Value::Number(num)) => Ok(num),
+                        _ => Err("`lhist` bounds must be constant numbers".to_string()),
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+
+                AggregateFunc::LHist(LinearBuckets::new(params[0], params[1], params[2])?)
+            }
+            "percentile" => {
+                let rank = args[1]
+                    .term()
+                    .and_then(|term| term.float_value())
+                    .map(|rank| (rank * 10_000.0).round())
+                    .filter(|rank| (1.0..10_000.0).contains(rank))
+                    .ok_or_else(|| {
+                        "percentile rank must be a number between 0 and 1, e.g. 0.99".to_string()
+                    })?;
+
+                AggregateFunc::Percentile(rank as u32)
+            }
+            name => return Err(format!("unknown aggregate function `{}`", name)),
+        })
+    }
+
+    /// Returns the number of histogram buckets, or `None` if the function is not a histogram.
+    pub fn bucket_count(&self) -> Option<u32> {
+        match self {
+            AggregateFunc::Hist => Some(LOG2_BUCKETS),
+            AggregateFunc::LHist(buckets) => Some(buckets.bucket_count()),
+            AggregateFunc::Percentile(_) => Some(LOG_LINEAR_BUCKETS),
+            _ => None,
+        }
+    }
+}
+
+/// Buckets of a linear histogram: `[min, min + step)`, `[min + step, min + 2 * step)`, ...
+/// Values below `min` and above `max` are counted in two additional buckets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LinearBuckets {
+    pub min: u64,
+    pub max: u64,
+    pub step: u64,
+}
+
+impl LinearBuckets {
+    fn new(min: u64, max: u64, step: u64) -> Result<Self, String> {
+        if step == 0 || max <= min {
+            return Err("`lhist` expects `min < max` and a non-zero step".to_string());
+        }
+
+        // count in 64 bits, so that huge ranges are not truncated before the check
+        match (max - min).div_ceil(step).checked_add(2) {
+            Some(count) if count <= MAX_LINEAR_BUCKETS => Ok(Self { min, max, step }),
+            _ => Err(format!(
+                "`lhist` can't have more than {} buckets",
+                MAX_LINEAR_BUCKETS
+            )),
+        }
+    }
+
+    /// Returns the number of buckets, including the ones for values out of bounds.
+    pub fn bucket_count(&self) -> u32 {
+        ((self.max - self.min).div_ceil(self.step) + 2) as u32
+    }
+}
+
+/// Aggregate function call.
+#[derive(Debug, Clone, Copy)]
+pub struct Aggregate<'a> {
+    pub func: AggregateFunc,
+    /// Aggregated value. `count` doesn't have one.
+    pub arg: Option<&'a FormulaExpr>,
+    /// Group key, e.g. `input.pid` in `count(input) by input.pid`.
+    pub key: Option<&'a FormulaExpr>,
+}
+
+impl FormulaExpr {
+    /// Returns an aggregate if the expression is an aggregate function call.
+    pub fn aggregate(&self) -> Option<Aggregate<'_>> {
+        match self {
+            FormulaExpr::Term(FormulaTerm::CountCall(_), _) => Some(Aggregate {
+                func: AggregateFunc::Count,
+                arg: None,
+                key: None,
+            }),
+            FormulaExpr::Call { func, args, .. } if AggregateFunc::is_aggregate(func) => {
+                Some(Aggregate {
+                    func: AggregateFunc::from_call(func, args).ok()?,
+                    arg: args.first(),
+                    key: None,
+                })
+            }
+            FormulaExpr::GroupBy { expr, key, .. } => Some(Aggregate {
+                key: Some(key),
+                ..expr.aggregate()?
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Aggregate state: an accumulator and a number of aggregated values.
+/// Stored as a pair of u64 in BPF maps; signed values are stored in two's complement.
+pub type AggregateState = [u64; 2];
+
+/// Merges aggregate states (e.g. from different CPUs) and returns the displayed value.
+/// Returns `None` if there are no values yet.
+pub fn aggregate_value(
+    func: AggregateFunc,
+    signed: bool,
+    states: &[AggregateState],
+) -> Option<String> {
+    let count = states.iter().map(|[_, count]| *count).sum::<u64>();
+    if count == 0 {
+        return None;
+    }
+
+    let sum = states
+        .iter()
+        .fold(0u64, |sum, [acc, _]| sum.wrapping_add(*acc));
+
+    // ignore states without values: their accumulators are not initialised
+    let accs = states
+        .iter()
+        .filter(|[_, count]| *count > 0)
+        .map(|[acc, _]| *acc);
+
+    Some(match (func, signed) {
+        (AggregateFunc::Count, _) => count.to_string(),
+        (AggregateFunc::Sum, true) => (sum as i64).to_string(),
+        (AggregateFunc::Sum, false) => sum.to_string(),
+        (AggregateFunc::Avg, true) => format!("{:.2}", sum as i64 as f64 / count as f64),
+        (AggregateFunc::Avg, false) => format!("{:.2}", sum as f64 / count as f64),
+        (AggregateFunc::Min, true) => accs.map(|acc| acc as i64).min()?.to_string(),
+        (AggregateFunc::Min, false) => accs.min()?.to_string(),
+        (AggregateFunc::Max, true) => accs.map(|acc| acc as i64).max()?.to_string(),
+        (AggregateFunc::Max, false) => accs.max()?.to_string(),
+        // histograms and percentiles are computed from bucket counts
+        (AggregateFunc::Hist | AggregateFunc::LHist(_) | AggregateFunc::Percentile(_), _) => {
+            return None
+        }
+    })
+}
+
+/// Row of a grouped aggregate table sent to the frontend.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct GroupRow {
+    pub key: String,
+    pub value: String,
+}
+
+/// Decodes a numeric group key read from a BPF map. Booleans are stored as 0 and 1.
+pub fn number_group_key(key_ty: ExprType, key: u64) -> Value {
+    match key_ty {
+        ExprType::SignedNumber => Value::SignedNumber(key as i64),
+        ExprType::Boolean => Value::Boolean(key != 0),
+        _ => Value::Number(key),
+    }
+}
+
+/// Decodes a string group key read from a BPF map.
+/// The key is NUL-terminated unless it occupies the whole buffer.
+pub fn str_group_key(key: &[u8]) -> Value {
+    let len = key.iter().position(|c| *c == 0).unwrap_or(key.len());
+    Value::String(String::from_utf8_lossy(&key[..len]).into_owned())
+}
+
+/// Computes aggregate values for each group. Rows are sorted by key;
+/// groups without values are omitted.
+pub fn group_rows(
+    func: AggregateFunc,
+    signed: bool,
+    mut groups: Vec<(Value, Vec<AggregateState>)>,
+) -> Vec<GroupRow> {
+    groups.sort_by(|(lhs, _), (rhs, _)| lhs.cmp(rhs));
+
+    groups
+        .into_iter()
+        .filter_map(|(key, states)| {
+            Some(GroupRow {
+                key: key.to_string(),
+                value: aggregate_value(func, signed, &states)?,
+            })
+        })
+        .collect()
+}
+
+/// Histogram bucket sent to the frontend.
+/// Bounds are `None` for buckets that are unbounded from one side.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct HistogramBucket {
+    pub min: Option<u64>,
+    /// Exclusive upper bound.
+    pub max: Option<u64>,
+    pub count: u64,
+}
+
+/// Returns histogram buckets for bucket counts read from a BPF map.
+/// Empty buckets on both ends of the histogram are omitted.
+pub fn histogram_buckets(func: AggregateFunc, counts: &[u64]) -> Vec<HistogramBucket> {
+    let last_bucket = counts.len().saturating_sub(1);
+
+    let buckets = counts.iter().enumerate().map(|(i, count)| {
+        let (min, max) = match func {
+            // values below 1 are counted in the first bucket
+            AggregateFunc::Hist if i == 0 => (None, Some(1)),
+            AggregateFunc::Hist => (Some(1 << (i - 1)), 1u64.checked_shl(i as u32)),
+            AggregateFunc::LHist(lin) if i == 0 => (None, Some(lin.min)),
+            AggregateFunc::LHist(lin) if i == last_bucket => (Some(lin.max), None),
+            AggregateFunc::LHist(lin) => {
+                let min = lin.min + (i as u64 - 1) * lin.step;
+                (Some(min), Some((min + lin.step).min(lin.max)))
+            }
+            _ => (None, None),
+        };
+
+        HistogramBucket {
+            min,
+            max,
+            count: *count,
+        }
+    });
+
+    let mut buckets = buckets
+        .skip_while(|bucket| bucket.count == 0)
+        .collect::<Vec<_>>();
+
+    while buckets.last().is_some_and(|bucket| bucket.count == 0) {
+        buckets.pop();
+    }
+
+    buckets
+}
+
+/// Returns the lower bound and the width of a log-linear histogram bucket.
+/// Values below `2^(LOG_LINEAR_SUB_BITS + 1)` have a bucket each.
+fn log_linear_bucket(index: u32) -> (u64, u64) {
+    let sub_buckets = 1 << LOG_LINEAR_SUB_BITS;
+    if index < sub_buckets {
+        return (index as u64, 1);
+    }
+
+    let shift = index / sub_buckets - 1;
+    let min = ((sub_buckets + index % sub_buckets) as u64) << shift;
+    (min, 1 << shift)
+}
+
+/// Returns bucket counts added since `prev_counts` were read, so that percentiles reflect
+/// only the latest interval. Missing previous counts are treated as zero.
+pub fn interval_counts(counts: &[u64], prev_counts: &[u64]) -> Vec<u64> {
+    counts
+        .iter()
+        .enumerate()
+        .map(|(index, count)| count.saturating_sub(prev_counts.get(index).copied().unwrap_or(0)))
+        .collect()
+}
+
+/// Estimates a percentile from log-linear histogram bucket counts using the nearest-rank method.
+/// Returns the middle of the bucket the percentile falls into, or `None` if there are no values.
+pub fn percentile_value(counts: &[u64], rank: f64) -> Option<u64> {
+    let total = counts.iter().sum::<u64>();
+    if total == 0 {
+        return None;
+    }
+
+    let target = ((rank * total as f64).ceil() as u64).clamp(1, total);
+    let mut seen = 0;
+
+    for (index, count) in counts.iter().enumerate() {
+        seen += count;
+        if seen >= target {
+            let (min, width) = log_linear_bucket(index as u32);
+            return Some(min + (width - 1) / 2);
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        aggregate_value, group_rows, histogram_buckets, interval_counts, log_linear_bucket,
+        number_group_key, percentile_value, str_group_key, AggregateFunc, HistogramBucket,
+        LinearBuckets, LOG_LINEAR_BUCKETS,
+    };
+    use crate::formulas::{parse_formula, ExprType};
+
+    #[test]
+    fn test_merge_aggregate_states() {
+        let states = [[10, 2], [0, 0], [(-4i64) as u64, 1]];
+
+        assert_eq!(
+            aggregate_value(AggregateFunc::Count, true, &states),
+            Some("3".to_string())
+        );
+        assert_eq!(
+            aggregate_value(AggregateFunc::Sum, true, &states),
+            Some("6".to_string())
+        );
+        assert_eq!(
+            aggregate_value(AggregateFunc::Avg, true, &states),
+            Some("2.00".to_string())
+        );
+        assert_eq!(
+            aggregate_value(AggregateFunc::Min, true, &states),
+            Some("-4".to_string())
+        );
+        assert_eq!(
+            aggregate_value(AggregateFunc::Max, false, &[[3, 1], [0, 0]]),
+            Some("3".to_string())
+        );
+        assert_eq!(aggregate_value(AggregateFunc::Sum, false, &[[0, 0]]), None);
+    }
+
+    #[test]
+    fn test_histogram_buckets() {
+        let lhist = parse_formula("lhist(input.pid, 10, 35, 10)").unwrap();
+        let func = lhist.aggregate().unwrap().func;
+
+        assert_eq!(func.bucket_count(), Some(5));
+        assert_eq!(
+            histogram_buckets(func, &[0, 1, 0, 2, 0]),
+            vec![
+                HistogramBucket {
+                    min: Some(10),
+                    max: Some(20),
+                    count: 1,
+                },
+                HistogramBucket {
+                    min: Some(20),
+                    max: Some(30),
+                    count: 0,
+                },
+                HistogramBucket {
+                    min: Some(30),
+                    max: Some(35),
+                    count: 2,
+                },
+            ]
+        );
+
+        assert!(LinearBuckets::new(0, 1022, 1).is_ok());
+        assert!(LinearBuckets::new(0, 1023, 1).is_err());
+        assert!(LinearBuckets::new(0, 4294967296, 1).is_err());
+        assert!(LinearBuckets::new(0, u64::MAX, 1).is_err());
+
+        let hist = histogram_buckets(AggregateFunc::Hist, &[1, 0, 3]);
+        assert_eq!((hist[0].min, hist[0].max), (None, Some(1)));
+        assert_eq!((hist[2].min, hist[2].max), (Some(2), Some(4)));
+    }
+
+    #[test]
+    fn test_group_rows() {
+        let formula = parse_formula("avg(input.size) by input.pid").unwrap();
+        let aggregate = formula.aggregate().unwrap();
+        assert_eq!(aggregate.func, AggregateFunc::Avg);
+        assert!(aggregate.key.is_some());
+
+        let rows = group_rows(
+            AggregateFunc::Sum,
+            false,
+            vec![
+                (number_group_key(ExprType::Number, 10), vec![[5, 1]]),
+                (number_group_key(ExprType::Number, 9), vec![[3, 1], [4, 2]]),
+                (number_group_key(ExprType::Number, 1), vec![[0, 0]]),
+            ],
+        );
+        let rows = rows
+            .iter()
+            .map(|row| (row.key.as_str(), row.value.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(rows, vec![("9", "7"), ("10", "5")]);
+
+        assert_eq!(str_group_key(b"bash\0\0\0").to_string(), "bash");
+        assert_eq!(
+            number_group_key(ExprType::SignedNumber, -1i64 as u64).to_string(),
+            "-1"
+        );
+    }
+
+    #[test]
+    fn test_percentile() {
+        let formula = parse_formula("percentile(input.size, 0.99)").unwrap();
+        assert_eq!(
+            formula.aggregate().unwrap().func,
+            AggregateFunc::Percentile(9900)
+        );
+        assert!(parse_formula("percentile(input.size, 1.5)")
+            .unwrap()
+            .aggregate()
+            .is_none());
+
+        // buckets are contiguous and cover all u64 values
+        assert_eq!(log_linear_bucket(16), (16, 1));
+        assert_eq!(log_linear_bucket(32), (32, 2));
+        let (min, width) = log_linear_bucket(LOG_LINEAR_BUCKETS - 1);
+        assert_eq!(min.checked_add(width), None);
+        assert_eq!(min + (width - 1), u64::MAX);
+
+        let mut counts = vec![0; LOG_LINEAR_BUCKETS as usize];
+        counts[1] = 90;
+        counts[48] = 9;
+        counts[49] = 1;
+        assert_eq!(percentile_value(&counts, 0.5), Some(1));
+        assert_eq!(percentile_value(&counts, 0.95), Some(65));
+        assert_eq!(percentile_value(&counts, 0.999), Some(69));
+        assert_eq!(percentile_value(&[0, 0], 0.5), None);
+    }
+
+    #[test]
+    fn test_interval_counts() {
+        assert_eq!(interval_counts(&[3, 0, 5], &[]), vec![3, 0, 5]);
+        assert_eq!(interval_counts(&[3, 2, 5], &[3, 0, 1]), vec![0, 2, 4]);
+
+        // earlier values don't affect percentiles of the latest interval
+        let prev_counts = [100, 0, 0];
+        let counts = interval_counts(&[100, 0, 10], &prev_counts);
+        assert_eq!(percentile_value(&counts, 0.5), Some(2));
+        assert_eq!(
+            percentile_value(&interval_counts(&prev_counts, &prev_counts), 0.5),
+            None
+        );
+    }
+}
@@ -0,0 +1,117 @@
+//! Builtin scalar functions.
+
+use super::{eval, ExprType, FormulaExpr, Value};
+
+/// Maximum size of a user memory read.
+pub const MAX_READ_LEN: u32 = 4096;
+
+/// Reads up to this size use the BPF stack, which is limited to 512 bytes;
+/// larger reads use per-CPU scratch maps. Reads of a runtime length are limited to this size.
+pub const MAX_STACK_READ_LEN: u32 = 128;
+
+/// Total size of read buffers a program may keep on the BPF stack; once it's reached,
+/// further reads use scratch maps regardless of their size.
+pub const MAX_STACK_READS_LEN: u32 = 256;
+
+/// Signature of a builtin function.
+#[derive(Debug)]
+pub struct Builtin {
+    pub name: &'static str,
+    pub args: &'static [ExprType],
+    pub ret: ExprType,
+    /// Whether the function can be evaluated only by a BPF program, e.g. `nstime()`.
+    pub runtime_only: bool,
+}
+
+/// All builtin functions except aggregates.
+const BUILTINS: &[Builtin] = &[
+    Builtin {
+        name: "env",
+        args: &[ExprType::String],
+        ret: ExprType::String,
+        runtime_only: false,
+    },
+    Builtin {
+        name: "len",
+        args: &[ExprType::String],
+        ret: ExprType::Number,
+        runtime_only: false,
+    },
+    Builtin {
+        name: "nstime",
+        args: &[],
+        ret: ExprType::Number,
+        runtime_only: true,
+    },
+    Builtin {
+        name: "cpu",
+        args: &[],
+        ret: ExprType::Number,
+        runtime_only: true,
+    },
+    Builtin {
+        name: "tid",
+        args: &[],
+        ret: ExprType::Number,
+        runtime_only: true,
+    },
+    Builtin {
+        name: "uid",
+        args: &[],
+        ret: ExprType::Number,
+        runtime_only: true,
+    },
+    Builtin {
+        name: "gid",
+        args: &[],
+        ret: ExprType::Number,
+        runtime_only: true,
+    },
+    Builtin {
+        name: "cgroup_id",
+        args: &[],
+        ret: ExprType::Number,
+        runtime_only: true,
+    },
+    Builtin {
+        name: "str",
+        args: &[ExprType::Number, ExprType::Number],
+        ret: ExprType::String,
+        runtime_only: true,
+    },
+    Builtin {
+        name: "buf",
+        args: &[ExprType::Number, ExprType::Number],
+        ret: ExprType::String,
+        runtime_only: true,
+    },
+];
+
+/// Size of a user memory read, e.g. `64` in `str(input.arg0, 64)`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReadSize {
+    Const(u32),
+    /// Known only at runtime; limited to `MAX_STACK_READ_LEN`.
+    Runtime,
+}
+
+/// Returns the size of a `str` or a `buf` read.
+/// `str` reads up to a NUL terminator, so its size must be a constant.
+pub fn read_size(func: &str, size: &FormulaExpr) -> Result<ReadSize, String> {
+    match eval(size) {
+        Ok(Value::Number(size)) if (1..=MAX_READ_LEN as u64).contains(&size) => {
+            Ok(ReadSize::Const(size as u32))
+        }
+        Err(_) if func == "buf" => Ok(ReadSize::Runtime),
+        _ => Err(format!(
+            "`{}` size must be a constant number between 1 and {}",
+            func, MAX_READ_LEN
+        )),
+    }
+}
+
+impl Builtin {
+    pub fn lookup(name: &str) -> Option<&'static Builtin> {
+        BUILTINS.iter().find(|builtin| builtin.name == name)
+    }
+}
@@ -1,33 +1,51 @@
 //! Code generator for the formula language.
 
 use inkwell::{
-    types::AnyTypeEnum,
-    values::{AnyValue, ArrayValue, PointerValue},
+    basic_block::BasicBlock,
+    types::{AnyTypeEnum, ArrayType},
+    values::{AnyValue, AnyValueEnum, ArrayValue, IntValue, PointerValue},
+    AddressSpace, IntPredicate,
 };
 
 use crate::{
-    codegen::{generate_string_literal, CodegenError},
-    formulas::{FormulaOp, FormulaTerm},
+    codegen::{
+        bpf_map_lookup_elem, call_bpf_helper, gen_bpf_helper, generate_bpf_map,
+        generate_map_lookup, generate_string_literal, CodegenError, SetMap, BPF_MAP_TYPE_HASH,
+        BPF_MAP_TYPE_PERCPU_ARRAY,
+    },
+    formulas::{
+        read_size, FormulaOp, FormulaTerm, ReadSize, UnaryOp, MAX_STACK_READS_LEN,
+        MAX_STACK_READ_LEN,
+    },
     nodes::{CodegenCtx, ExprValue, OutputType},
 };
 
 use super::{ExprType, FormulaExpr};
 
+/// Sets larger than this are checked with a BPF hash map lookup instead of unrolled comparisons.
+const MAX_UNROLLED_SET_LEN: usize = 8;
+
 pub fn generate_expr_code<'a>(
     ctx: &mut CodegenCtx<'a>,
     expr: &FormulaExpr,
     prev_node_output: Option<&OutputType>,
 ) -> Result<ExprValue<'a>, CodegenError> {
     match expr {
-        FormulaExpr::Term(term) => {
+        FormulaExpr::Term(term, _) => {
             match term {
                 FormulaTerm::Property(ref object, ref prop_name) => {
-                    assert_eq!(object, "input"); // we support only one type of properties for now
+                    if object != "input" {
+                        // we support only one type of properties for now
+                        return Err(CodegenError::Other(format!("unknown object: {}", object)));
+                    }
 
                     if let Some(OutputType::Struct(prev_output_struct)) = prev_node_output {
                         return Ok(prev_output_struct.codegen_lookup(prop_name, ctx)?);
                     } else {
-                        panic!("unexpected input type: {:?}", prev_node_output);
+                        return Err(CodegenError::Other(format!(
+                            "unexpected input type: {:?}",
+                            prev_node_output
+                        )));
                     }
                 }
                 FormulaTerm::String(str_literal) => {
@@ -37,131 +55,1022 @@ pub fn generate_expr_code<'a>(
                         ty: ExprType::String,
                     })
                 }
+                FormulaTerm::Number(num) => Ok(ExprValue {
+                    value: ctx.llvm_context.i64_type().const_int(*num, false).into(),
+                    ty: ExprType::Number,
+                }),
+                FormulaTerm::SignedNumber(num) => Ok(ExprValue {
+                    value: ctx
+                        .llvm_context
+                        .i64_type()
+                        .const_int(*num as u64, true)
+                        .into(),
+                    ty: ExprType::SignedNumber,
+                }),
+                FormulaTerm::Boolean(val) => Ok(ExprValue {
+                    value: ctx
+                        .llvm_context
+                        .bool_type()
+                        .const_int(*val as u64, false)
+                        .into(),
+                    ty: ExprType::Boolean,
+                }),
                 FormulaTerm::CountCall(_ident) => Ok(ExprValue {
                     value: ctx.llvm_context.i64_type().const_int(1, false).into(),
                     ty: ExprType::Counter,
                 }),
-                _ => todo!(),
+                FormulaTerm::Float(_) => Err(CodegenError::Other(
+                    "fractional numbers can be used only as a percentile rank".to_string(),
+                )),
             }
         }
+        FormulaExpr::Binary { binary_op, .. }
+            if binary_op.is_comparison() || binary_op.is_logical() || binary_op.is_membership() =>
+        {
+            // materialise the condition result as a boolean value
+            let false_block = ctx.llvm_context.append_basic_block(ctx.func, "cond_false");
+            let merge_block = ctx.llvm_context.append_basic_block(ctx.func, "cond_merge");
+
+            generate_cond_code(ctx, expr, prev_node_output, false_block)?;
+            let true_block = ctx.current_block;
+            ctx.builder.build_unconditional_branch(merge_block);
+
+            ctx.set_current_block(false_block);
+            ctx.builder.build_unconditional_branch(merge_block);
+
+            ctx.set_current_block(merge_block);
+
+            let bool_ty = ctx.llvm_context.bool_type();
+            let phi = ctx.builder.build_phi(bool_ty, "cond");
+            phi.add_incoming(&[
+                (&bool_ty.const_int(1, false), true_block),
+                (&bool_ty.const_zero(), false_block),
+            ]);
+
+            Ok(ExprValue {
+                value: phi.as_any_value_enum(),
+                ty: ExprType::Boolean,
+            })
+        }
         FormulaExpr::Binary {
             lhs,
             rhs,
             binary_op,
+            ..
         } => {
             let lhs_expr = generate_expr_code(ctx, lhs, prev_node_output)?;
             let rhs_expr = generate_expr_code(ctx, rhs, prev_node_output)?;
 
-            match (lhs_expr.ty, rhs_expr.ty) {
-                (ExprType::String, ExprType::String) => {
-                    // compare two strings
-                    if *binary_op != FormulaOp::Eq && *binary_op != FormulaOp::NotEq {
-                        // TODO: fix panic
-                        panic!("invalid comparison operation for strings");
-                    }
-                    // determine if lhs/rhs is a literal and hasn't generated any value
-                    match (&lhs_expr.value.get_type(), &rhs_expr.value.get_type()) {
-                        (AnyTypeEnum::ArrayType(_), AnyTypeEnum::ArrayType(_)) => {
-                            // comparing two literals
-                            todo!();
-                        }
-                        (AnyTypeEnum::PointerType(_), AnyTypeEnum::ArrayType(_))
-                        | (AnyTypeEnum::ArrayType(_), AnyTypeEnum::PointerType(_)) => {
-                            // comparing prop and a literal
-                            // swap lhs with rhs if we're comparing literal with an expr
-                            let (expr_val, literal) = if lhs_expr.value.is_array_value() {
-                                (rhs_expr, lhs_expr)
-                            } else {
-                                (lhs_expr, rhs_expr)
-                            };
-
-                            // generate a sequence of icmps for each character
-                            // TODO: check for string length
-                            Ok(generate_string_cmp(
-                                ctx,
-                                *binary_op,
-                                expr_val.value.into_pointer_value(),
-                                literal.value.into_array_value(),
-                            ))
-                        }
-                        (_, _) => {
-                            // comparing two exprs
-                            // TODO
-                            todo!();
-                        }
-                    }
-                }
-                (ExprType::Number, ExprType::Number) => {
-                    // compare two nums
-                    // ctx.builder.build_int_compare();
-                    todo!();
-                }
-                (ExprType::Boolean, ExprType::Number) | (ExprType::Number, ExprType::Boolean) => {
-                    // compare bool with a number
-                    todo!();
+            generate_arith(ctx, *binary_op, lhs_expr, rhs_expr)
+        }
+        FormulaExpr::Unary { expr, unary_op, .. } => {
+            let expr_val = generate_expr_code(ctx, expr, prev_node_output)?;
+
+            match (unary_op, expr_val.ty) {
+                (UnaryOp::Not, ExprType::Boolean) => Ok(ExprValue {
+                    value: ctx
+                        .builder
+                        .build_not(expr_val.value.into_int_value(), "not")
+                        .as_any_value_enum(),
+                    ty: ExprType::Boolean,
+                }),
+                (UnaryOp::Not, ty) if ty.is_numeric() => {
+                    // numbers are true if they're non-zero
+                    let int_val = expr_val.value.into_int_value();
+                    Ok(ExprValue {
+                        value: ctx
+                            .builder
+                            .build_int_compare(
+                                IntPredicate::EQ,
+                                int_val,
+                                int_val.get_type().const_zero(),
+                                "not",
+                            )
+                            .as_any_value_enum(),
+                        ty: ExprType::Boolean,
+                    })
                 }
-                (ExprType::Boolean, ExprType::Boolean) => {
-                    // compare two bools
-                    todo!();
+                (UnaryOp::Neg, ty) if ty.is_numeric() => {
+                    let int_val = widen_int(
+                        ctx,
+                        expr_val.value.into_int_value(),
+                        ty == ExprType::SignedNumber,
+                    );
+                    Ok(ExprValue {
+                        value: ctx
+                            .builder
+                            .build_int_neg(int_val, "neg")
+                            .as_any_value_enum(),
+                        ty: ExprType::SignedNumber,
+                    })
                 }
-                (ExprType::Counter, _) | (_, ExprType::Counter) => {
-                    panic!("invalid comparison with a counter");
+                (unary_op, ty) => Err(CodegenError::Other(format!(
+                    "invalid operand for {:?}: {:?}",
+                    unary_op, ty
+                ))),
+            }
+        }
+        FormulaExpr::Call { func, args, .. } => generate_call(ctx, func, args, prev_node_output),
+        FormulaExpr::List(..) => Err(CodegenError::Other(
+            "lists can be used only with `in` and `not in`".to_string(),
+        )),
+        FormulaExpr::GroupBy { .. } => Err(CodegenError::Other(
+            "grouped aggregates can be used only as a node value".to_string(),
+        )),
+    }
+}
+
+/// Generates a call to a builtin function.
+fn generate_call<'a>(
+    ctx: &mut CodegenCtx<'a>,
+    func: &str,
+    args: &[FormulaExpr],
+    prev_node_output: Option<&OutputType>,
+) -> Result<ExprValue<'a>, CodegenError> {
+    let i64_ty = ctx.llvm_context.i64_type();
+    let low_bits = i64_ty.const_int(u32::MAX as u64, false);
+    let high_bits_shift = i64_ty.const_int(32, false);
+
+    let value = match (func, args) {
+        ("nstime", []) => call_bpf_helper(ctx, 5, "ktime_ns"),
+        ("cpu", []) => call_bpf_helper(ctx, 8, "cpu"),
+        ("tid", []) => {
+            // the lower 32 bits of pid_tgid are the thread id
+            let pid_tgid = call_bpf_helper(ctx, 14, "pid_tgid");
+            ctx.builder.build_and(pid_tgid, low_bits, "tid")
+        }
+        ("uid", []) => {
+            let uid_gid = call_bpf_helper(ctx, 15, "uid_gid");
+            ctx.builder.build_and(uid_gid, low_bits, "uid")
+        }
+        ("gid", []) => {
+            let uid_gid = call_bpf_helper(ctx, 15, "uid_gid");
+            ctx.builder
+                .build_right_shift(uid_gid, high_bits_shift, false, "gid")
+        }
+        ("cgroup_id", []) => call_bpf_helper(ctx, 80, "cgroup_id"),
+        ("str" | "buf", [ptr, size]) => {
+            let buf = generate_user_read(ctx, func, ptr, size, prev_node_output)?;
+            return Ok(ExprValue {
+                value: buf.as_any_value_enum(),
+                ty: ExprType::String,
+            });
+        }
+        ("len", [arg]) => {
+            let arg_val = generate_expr_code(ctx, arg, prev_node_output)?;
+            if arg_val.ty != ExprType::String {
+                return Err(CodegenError::Other(format!(
+                    "`len` expects a string, found a {}",
+                    arg_val.ty
+                )));
+            }
+            generate_strlen(ctx, StrOperand::from_value(arg_val.value)?)
+        }
+        (func, _) => {
+            return Err(CodegenError::Other(format!(
+                "function `{}` can't be used in BPF code",
+                func
+            )))
+        }
+    };
+
+    Ok(ExprValue {
+        value: value.as_any_value_enum(),
+        ty: ExprType::Number,
+    })
+}
+
+/// Reads a string (`str`) or a buffer (`buf`) from user memory.
+/// Returns a pointer to the buffer. `str` results are NUL-terminated, but bytes after the
+/// terminator are only zeroed for reads of a runtime length; code that uses the whole buffer,
+/// e.g. hashing, must stop at the terminator.
+fn generate_user_read<'a>(
+    ctx: &mut CodegenCtx<'a>,
+    func: &str,
+    ptr: &FormulaExpr,
+    size: &FormulaExpr,
+    prev_node_output: Option<&OutputType>,
+) -> Result<PointerValue<'a>, CodegenError> {
+    let i32_ty = ctx.llvm_context.i32_type();
+    let i8_ptr_ty = ctx.llvm_context.i8_type().ptr_type(AddressSpace::Generic);
+
+    let read_size = read_size(func, size).map_err(CodegenError::Other)?;
+    let buf_len = match read_size {
+        ReadSize::Const(len) => len,
+        ReadSize::Runtime => MAX_STACK_READ_LEN,
+    };
+    let buf_ty = ctx.llvm_context.i8_type().array_type(buf_len);
+
+    let src = generate_expr_code(ctx, ptr, prev_node_output)?;
+    if !src.ty.is_numeric() {
+        return Err(CodegenError::Other(format!(
+            "`{}` expects a pointer, found a {}",
+            func, src.ty
+        )));
+    }
+    let src = ctx
+        .builder
+        .build_int_to_ptr(src.value.into_int_value(), i8_ptr_ty, "src");
+
+    let buf = generate_read_buffer(ctx, buf_ty);
+
+    let len = match read_size {
+        ReadSize::Const(len) => i32_ty.const_int(len as u64, false),
+        ReadSize::Runtime => {
+            // a partial read leaves stale data in the buffer
+            ctx.builder.build_store(buf, buf_ty.const_zero());
+
+            let len = generate_expr_code(ctx, size, prev_node_output)?;
+            let len = widen_int(ctx, len.value.into_int_value(), false);
+            let max_len = len.get_type().const_int(buf_len as u64, false);
+            let above_max =
+                ctx.builder
+                    .build_int_compare(IntPredicate::UGT, len, max_len, "above_max");
+            let len = ctx
+                .builder
+                .build_select(above_max, max_len, len, "len")
+                .into_int_value();
+
+            ctx.builder.build_int_truncate(len, i32_ty, "len")
+        }
+    };
+
+    // bpf_probe_read_user_str and bpf_probe_read_user zero the buffer on failure
+    let helper_num = if func == "str" { 114 } else { 112 };
+    let bpf_probe_read = gen_bpf_helper(
+        ctx,
+        helper_num,
+        ctx.llvm_context.i64_type().fn_type(
+            &[
+                i8_ptr_ty.into(), // dst
+                i32_ty.into(),    // size
+                i8_ptr_ty.into(), // unsafe_ptr
+            ],
+            false,
+        ),
+    );
+    let dst = ctx.builder.build_pointer_cast(buf, i8_ptr_ty, "dst");
+    ctx.builder.build_call(
+        bpf_probe_read,
+        &[dst.into(), len.into(), src.into()],
+        "probe_read_user",
+    );
+
+    Ok(buf)
+}
+
+/// Returns a buffer for data read from memory: on the BPF stack while it has room for it,
+/// otherwise in a scratch map. The stack is limited to 512 bytes, so small reads that would
+/// exceed `MAX_STACK_READS_LEN` together use scratch maps as well.
+pub fn generate_read_buffer<'a>(
+    ctx: &mut CodegenCtx<'a>,
+    buf_ty: ArrayType<'a>,
+) -> PointerValue<'a> {
+    let len = buf_ty.len();
+
+    if len <= MAX_STACK_READ_LEN && ctx.stack_reads_len + len <= MAX_STACK_READS_LEN {
+        ctx.stack_reads_len += len;
+
+        ctx.builder.position_at_end(ctx.allocs_block);
+        let buf = ctx.builder.build_alloca(buf_ty, "read_buf");
+        ctx.builder.position_at_end(ctx.current_block);
+        buf
+    } else {
+        generate_scratch_buffer(ctx, buf_ty)
+    }
+}
+
+/// Returns a buffer in a per-CPU BPF array, for data that doesn't fit into the BPF stack.
+/// Each buffer has its own map, so that buffers used in the same expression don't overlap.
+fn generate_scratch_buffer<'a>(
+    ctx: &mut CodegenCtx<'a>,
+    buf_ty: ArrayType<'a>,
+) -> PointerValue<'a> {
+    let name = (0..)
+        .map(|i| format!("scratch_{}", i))
+        .find(|name| ctx.module.get_global(name).is_none())
+        .expect("no free scratch map names");
+
+    let map = generate_bpf_map(ctx, &name, BPF_MAP_TYPE_PERCPU_ARRAY, 4, buf_ty.len(), 1);
+
+    generate_map_lookup(
+        ctx,
+        map.as_pointer_value(),
+        ctx.llvm_context.i32_type().const_zero(),
+        buf_ty.ptr_type(AddressSpace::Generic),
+    )
+}
+
+/// Returns the length of a string: the index of the NUL terminator, or the buffer size
+/// if the string occupies the whole buffer.
+fn generate_strlen<'a>(ctx: &mut CodegenCtx<'a>, str: StrOperand<'a>) -> IntValue<'a> {
+    let i64_ty = ctx.llvm_context.i64_type();
+    let nul = ctx.llvm_context.i8_type().const_zero();
+
+    // scan backwards, so that the first terminator wins
+    let mut len = i64_ty.const_int(str.len() as u64, false);
+
+    for index in (0..str.len()).rev() {
+        let char = str.load_char(ctx, index);
+        let is_nul = ctx
+            .builder
+            .build_int_compare(IntPredicate::EQ, char, nul, "is_nul");
+        len = ctx
+            .builder
+            .build_select(is_nul, i64_ty.const_int(index as u64, false), len, "len")
+            .into_int_value();
+    }
+
+    len
+}
+
+/// Generates code for a boolean condition.
+/// Branches to `false_block` if the condition doesn't hold; otherwise, the execution
+/// continues in the current block. Logical operators are short-circuiting.
+pub fn generate_cond_code<'a>(
+    ctx: &mut CodegenCtx<'a>,
+    expr: &FormulaExpr,
+    prev_node_output: Option<&OutputType>,
+    false_block: BasicBlock<'a>,
+) -> Result<(), CodegenError> {
+    match expr {
+        FormulaExpr::Binary {
+            lhs,
+            rhs,
+            binary_op: FormulaOp::And,
+            ..
+        } => {
+            // if lhs is false, rhs is not evaluated
+            generate_cond_code(ctx, lhs, prev_node_output, false_block)?;
+            generate_cond_code(ctx, rhs, prev_node_output, false_block)
+        }
+        FormulaExpr::Binary {
+            lhs,
+            rhs,
+            binary_op: FormulaOp::Or,
+            ..
+        } => {
+            // if lhs is true, rhs is not evaluated
+            let rhs_block = ctx.llvm_context.append_basic_block(ctx.func, "or_rhs");
+            let true_block = ctx.llvm_context.append_basic_block(ctx.func, "or_true");
+
+            generate_cond_code(ctx, lhs, prev_node_output, rhs_block)?;
+            ctx.builder.build_unconditional_branch(true_block);
+
+            ctx.set_current_block(rhs_block);
+            generate_cond_code(ctx, rhs, prev_node_output, false_block)?;
+            ctx.builder.build_unconditional_branch(true_block);
+
+            ctx.set_current_block(true_block);
+            Ok(())
+        }
+        FormulaExpr::Binary {
+            lhs,
+            rhs,
+            binary_op,
+            ..
+        } if binary_op.is_membership() => {
+            generate_membership_cond(ctx, *binary_op, lhs, rhs, prev_node_output, false_block)
+        }
+        FormulaExpr::Binary {
+            lhs,
+            rhs,
+            binary_op,
+            ..
+        } if binary_op.is_comparison() => {
+            let lhs_expr = generate_expr_code(ctx, lhs, prev_node_output)?;
+            let rhs_expr = generate_expr_code(ctx, rhs, prev_node_output)?;
+
+            generate_cmp(ctx, *binary_op, lhs_expr, rhs_expr, false_block)
+        }
+        FormulaExpr::Unary {
+            expr,
+            unary_op: UnaryOp::Not,
+            ..
+        } => {
+            // invert the condition: continue if it's false
+            let not_block = ctx.llvm_context.append_basic_block(ctx.func, "not_true");
+
+            generate_cond_code(ctx, expr, prev_node_output, not_block)?;
+            ctx.builder.build_unconditional_branch(false_block);
+
+            ctx.set_current_block(not_block);
+            Ok(())
+        }
+        expr => {
+            // non-comparison expressions are true if they evaluate to a non-zero value
+            let expr_val = generate_expr_code(ctx, expr, prev_node_output)?;
+
+            if expr_val.ty != ExprType::Boolean && !expr_val.ty.is_numeric() {
+                return Err(CodegenError::Other(format!(
+                    "expected a boolean condition, found {:?}",
+                    expr_val.ty
+                )));
+            }
+
+            let int_val = expr_val.value.into_int_value();
+            let cmp_res = ctx.builder.build_int_compare(
+                IntPredicate::NE,
+                int_val,
+                int_val.get_type().const_zero(),
+                "is_true",
+            );
+
+            let next_block = ctx.llvm_context.append_basic_block(ctx.func, "cond_br");
+            ctx.builder
+                .build_conditional_branch(cmp_res, next_block, false_block);
+
+            ctx.set_current_block(next_block);
+            Ok(())
+        }
+    }
+}
+
+/// Generates a check for a set membership or a substring.
+/// Branches to `false_block` if the check doesn't hold.
+fn generate_membership_cond<'a>(
+    ctx: &mut CodegenCtx<'a>,
+    binary_op: FormulaOp,
+    lhs: &FormulaExpr,
+    rhs: &FormulaExpr,
+    prev_node_output: Option<&OutputType>,
+    false_block: BasicBlock<'a>,
+) -> Result<(), CodegenError> {
+    let found_block = ctx.llvm_context.append_basic_block(ctx.func, "in_found");
+    let not_found_block = ctx
+        .llvm_context
+        .append_basic_block(ctx.func, "in_not_found");
+
+    // lhs is evaluated only once
+    let lhs_expr = generate_expr_code(ctx, lhs, prev_node_output)?;
+
+    match rhs {
+        FormulaExpr::List(elems, _) if elems.len() > MAX_UNROLLED_SET_LEN => {
+            let values = elems
+                .iter()
+                .map(|elem| match elem.term() {
+                    Some(FormulaTerm::Number(num)) => Some(*num),
+                    Some(FormulaTerm::SignedNumber(num)) => Some(*num as u64),
+                    _ => None,
+                })
+                .collect::<Option<Vec<_>>>();
+
+            match values {
+                Some(values) if lhs_expr.ty.is_numeric() => {
+                    generate_set_lookup(ctx, lhs_expr, values, found_block, not_found_block);
                 }
-                (ExprType::String, _) | (_, ExprType::String) => {
-                    // type mismatch - comparing string with numeric/boolean value
-                    // TODO: fix panic
-                    panic!("type mismatch");
+                _ => {
+                    return Err(CodegenError::Other(format!(
+                        "sets with more than {} elements must contain only number literals",
+                        MAX_UNROLLED_SET_LEN
+                    )));
                 }
             }
         }
+        FormulaExpr::List(elems, _) => {
+            // unroll comparisons for small sets
+            for elem in elems {
+                let next_block = ctx.llvm_context.append_basic_block(ctx.func, "in_next");
+
+                let elem_expr = generate_expr_code(ctx, elem, prev_node_output)?;
+                generate_cmp(ctx, FormulaOp::Eq, lhs_expr.clone(), elem_expr, next_block)?;
+                ctx.builder.build_unconditional_branch(found_block);
+
+                ctx.set_current_block(next_block);
+            }
+            ctx.builder.build_unconditional_branch(not_found_block);
+        }
+        rhs => {
+            let rhs_expr = generate_expr_code(ctx, rhs, prev_node_output)?;
+            generate_substring_search(ctx, lhs_expr, rhs_expr, found_block, not_found_block)?;
+        }
     }
+
+    let (true_block, fail_block) = if binary_op == FormulaOp::NotIn {
+        (not_found_block, found_block)
+    } else {
+        (found_block, not_found_block)
+    };
+
+    ctx.set_current_block(fail_block);
+    ctx.builder.build_unconditional_branch(false_block);
+
+    ctx.set_current_block(true_block);
+    Ok(())
 }
 
-fn generate_string_cmp<'a>(
+/// Generates a set membership check using a BPF hash map lookup.
+/// The map is populated with `values` at load time.
+fn generate_set_lookup<'a>(
     ctx: &mut CodegenCtx<'a>,
-    binary_op: FormulaOp,
-    lhs: PointerValue<'a>,
-    rhs: ArrayValue<'a>,
-) -> ExprValue<'a> {
-    dbg!(&lhs, &rhs);
+    lhs: ExprValue<'a>,
+    mut values: Vec<u64>,
+    found_block: BasicBlock<'a>,
+    not_found_block: BasicBlock<'a>,
+) {
+    values.sort_unstable();
+    values.dedup();
+
+    let name = format!("set_{}", ctx.set_maps.len());
+    let map = generate_bpf_map(ctx, &name, BPF_MAP_TYPE_HASH, 8, 1, values.len() as u32);
+    ctx.set_maps.push(SetMap { name, values });
 
     let i64_ty = ctx.llvm_context.i64_type();
 
-    for char_num in 0..rhs.get_type().len() {
-        let next_block = ctx.llvm_context.append_basic_block(ctx.func, "cmp_br");
+    ctx.builder.position_at_end(ctx.allocs_block);
+    let key = ctx.builder.build_alloca(i64_ty, "set_key");
+    ctx.builder.position_at_end(ctx.current_block);
 
-        let lhs_elem_ptr = unsafe {
-            ctx.builder.build_in_bounds_gep(
-                lhs,
-                &[
-                    i64_ty.const_int(0, false),
-                    i64_ty.const_int(char_num as u64, false),
-                ],
-                "v",
-            )
-        };
+    let key_val = widen_int(
+        ctx,
+        lhs.value.into_int_value(),
+        lhs.ty == ExprType::SignedNumber,
+    );
+    ctx.builder.build_store(key, key_val);
 
-        let lhs_elem = ctx.builder.build_load(lhs_elem_ptr, "i").into_int_value();
+    let value_ptr = bpf_map_lookup_elem(
+        ctx,
+        map.as_pointer_value(),
+        key,
+        ctx.llvm_context.i8_type().ptr_type(AddressSpace::Generic),
+    );
+    let found = ctx.builder.build_is_not_null(value_ptr, "found");
 
-        // extract value from the literal rhs
-        let rhs_elem = ctx
-            .builder
-            .build_extract_value(rhs, char_num, "literal")
-            .expect("could not extract value")
-            .into_int_value();
+    ctx.builder
+        .build_conditional_branch(found, found_block, not_found_block);
+}
+
+/// Generates a search for a string literal within a string buffer.
+fn generate_substring_search<'a>(
+    ctx: &mut CodegenCtx<'a>,
+    needle: ExprValue<'a>,
+    haystack: ExprValue<'a>,
+    found_block: BasicBlock<'a>,
+    not_found_block: BasicBlock<'a>,
+) -> Result<(), CodegenError> {
+    let (needle, haystack) = match (needle.value, haystack.value) {
+        (AnyValueEnum::ArrayValue(needle), AnyValueEnum::PointerValue(haystack)) => {
+            (needle, haystack)
+        }
+        _ => {
+            return Err(CodegenError::Other(
+                "`in` expects a list or a string literal searched within a string".to_string(),
+            ))
+        }
+    };
+
+    let needle_len = needle.get_type().len();
+    let haystack_len = haystack
+        .get_type()
+        .get_element_type()
+        .into_array_type()
+        .len();
+
+    if needle_len == 0 {
+        ctx.builder.build_unconditional_branch(found_block);
+        return Ok(());
+    }
+
+    for start in 0..haystack_len.saturating_sub(needle_len - 1) {
+        // stop at the string terminator
+        let first_char = generate_load_char(ctx, haystack, start);
+        let is_end = ctx.builder.build_int_compare(
+            IntPredicate::EQ,
+            first_char,
+            first_char.get_type().const_zero(),
+            "is_end",
+        );
+        let cmp_block = ctx.llvm_context.append_basic_block(ctx.func, "substr_cmp");
+        ctx.builder
+            .build_conditional_branch(is_end, not_found_block, cmp_block);
+        ctx.set_current_block(cmp_block);
+
+        let next_pos_block = ctx.llvm_context.append_basic_block(ctx.func, "substr_next");
+
+        for char_num in 0..needle_len {
+            let haystack_char = generate_load_char(ctx, haystack, start + char_num);
+            let needle_char = ctx
+                .builder
+                .build_extract_value(needle, char_num, "literal")
+                .expect("could not extract value")
+                .into_int_value();
+
+            let cmp_res =
+                ctx.builder
+                    .build_int_compare(IntPredicate::EQ, haystack_char, needle_char, "cmp");
+
+            let next_block = ctx.llvm_context.append_basic_block(ctx.func, "cmp_br");
+            ctx.builder
+                .build_conditional_branch(cmp_res, next_block, next_pos_block);
+            ctx.set_current_block(next_block);
+        }
+
+        ctx.builder.build_unconditional_branch(found_block);
+        ctx.set_current_block(next_pos_block);
+    }
+
+    ctx.builder.build_unconditional_branch(not_found_block);
+    Ok(())
+}
+
+/// Loads a character at the given index from a string buffer.
+fn generate_load_char<'a>(
+    ctx: &mut CodegenCtx<'a>,
+    str_ptr: PointerValue<'a>,
+    index: u32,
+) -> IntValue<'a> {
+    let i64_ty = ctx.llvm_context.i64_type();
+
+    let elem_ptr = unsafe {
+        ctx.builder.build_in_bounds_gep(
+            str_ptr,
+            &[
+                i64_ty.const_int(0, false),
+                i64_ty.const_int(index as u64, false),
+            ],
+            "v",
+        )
+    };
+
+    ctx.builder.build_load(elem_ptr, "i").into_int_value()
+}
+
+/// Generates a comparison of two values.
+fn generate_cmp<'a>(
+    ctx: &mut CodegenCtx<'a>,
+    binary_op: FormulaOp,
+    lhs_expr: ExprValue<'a>,
+    rhs_expr: ExprValue<'a>,
+    false_block: BasicBlock<'a>,
+) -> Result<(), CodegenError> {
+    match (lhs_expr.ty, rhs_expr.ty) {
+        (ExprType::String, ExprType::String) => {
+            // compare two strings
+            if binary_op != FormulaOp::Eq && binary_op != FormulaOp::NotEq {
+                return Err(CodegenError::Other(format!(
+                    "invalid comparison operation for strings: {:?}",
+                    binary_op
+                )));
+            }
+            // determine if lhs/rhs is a literal and hasn't generated any value
+            match (&lhs_expr.value.get_type(), &rhs_expr.value.get_type()) {
+                (AnyTypeEnum::ArrayType(_), AnyTypeEnum::ArrayType(_)) => {
+                    // comparing two literals. normally they're removed by the constant folding,
+                    // but we can still compare them at compile time: LLVM constants are uniqued,
+                    // so equal literals are represented by the same value
+                    let equal = lhs_expr.value == rhs_expr.value;
+
+                    if equal != (binary_op == FormulaOp::Eq) {
+                        ctx.builder.build_unconditional_branch(false_block);
+
+                        let unreachable_block = ctx
+                            .llvm_context
+                            .append_basic_block(ctx.func, "cmp_unreachable");
+                        ctx.set_current_block(unreachable_block);
+                    }
+                    Ok(())
+                }
+                (_, _) => {
+                    // comparing string buffers with each other or with a literal
+                    let lhs = StrOperand::from_value(lhs_expr.value)?;
+                    let rhs = StrOperand::from_value(rhs_expr.value)?;
+
+                    generate_string_cmp(ctx, binary_op, lhs, rhs, false_block);
+                    Ok(())
+                }
+            }
+        }
+        (lhs_ty, rhs_ty) if lhs_ty.is_numeric() && rhs_ty.is_numeric() => {
+            // compare two nums
+            generate_int_cmp(ctx, binary_op, lhs_expr, rhs_expr, false_block)
+        }
+        (ExprType::Boolean, ExprType::Boolean) => {
+            // compare two bools
+            if binary_op != FormulaOp::Eq && binary_op != FormulaOp::NotEq {
+                return Err(CodegenError::Other(format!(
+                    "invalid comparison operation for booleans: {:?}",
+                    binary_op
+                )));
+            }
+            generate_int_cmp(ctx, binary_op, lhs_expr, rhs_expr, false_block)
+        }
+        (lhs_ty, rhs_ty) => {
+            // type mismatch; normally rejected by the type checker before codegen
+            Err(CodegenError::Other(format!(
+                "cannot compare {} with {}",
+                lhs_ty, rhs_ty
+            )))
+        }
+    }
+}
+
+/// String operand of a comparison.
+enum StrOperand<'a> {
+    /// Pointer to a NUL-terminated string buffer.
+    /// A string can occupy the whole buffer, in which case it has no terminator.
+    Buffer(PointerValue<'a>),
+    /// String literal without a terminator.
+    Literal(ArrayValue<'a>),
+}
+
+impl<'a> StrOperand<'a> {
+    fn from_value(value: AnyValueEnum<'a>) -> Result<Self, CodegenError> {
+        match value {
+            AnyValueEnum::PointerValue(ptr)
+                if ptr.get_type().get_element_type().is_array_type() =>
+            {
+                Ok(StrOperand::Buffer(ptr))
+            }
+            AnyValueEnum::ArrayValue(literal) => Ok(StrOperand::Literal(literal)),
+            value => Err(CodegenError::Other(format!(
+                "unexpected string value: {:?}",
+                value
+            ))),
+        }
+    }
+
+    fn len(&self) -> u32 {
+        match self {
+            StrOperand::Buffer(ptr) => ptr.get_type().get_element_type().into_array_type().len(),
+            StrOperand::Literal(literal) => literal.get_type().len(),
+        }
+    }
+
+    /// Returns a character at `index`, or a NUL terminator if the index is out of bounds.
+    fn load_char(&self, ctx: &mut CodegenCtx<'a>, index: u32) -> IntValue<'a> {
+        if index >= self.len() {
+            return ctx.llvm_context.i8_type().const_zero();
+        }
+
+        match self {
+            StrOperand::Buffer(ptr) => generate_load_char(ctx, *ptr, index),
+            StrOperand::Literal(literal) => ctx
+                .builder
+                .build_extract_value(*literal, index, "literal")
+                .expect("could not extract value")
+                .into_int_value(),
+        }
+    }
+}
+
+/// Generates an equality check for two strings.
+/// Strings are compared up to the NUL terminator or the end of the shorter buffer, so that
+/// e.g. "bashful" doesn't match "bash".
+fn generate_string_cmp<'a>(
+    ctx: &mut CodegenCtx<'a>,
+    binary_op: FormulaOp,
+    lhs: StrOperand<'a>,
+    rhs: StrOperand<'a>,
+    false_block: BasicBlock<'a>,
+) {
+    let equal_block = ctx.llvm_context.append_basic_block(ctx.func, "str_equal");
+    let not_equal_block = ctx
+        .llvm_context
+        .append_basic_block(ctx.func, "str_not_equal");
+
+    // compare characters up to the end of the longest buffer, as the other one is NUL-padded.
+    // literals are known to end at their length, so there's no need to look further
+    let mut cmp_len = lhs.len().max(rhs.len());
+    for operand in [&lhs, &rhs] {
+        if let StrOperand::Literal(literal) = operand {
+            cmp_len = cmp_len.min(literal.get_type().len() + 1);
+        }
+    }
+
+    for char_num in 0..cmp_len {
+        let lhs_char = lhs.load_char(ctx, char_num);
+        let rhs_char = rhs.load_char(ctx, char_num);
 
         let cmp_res = ctx
             .builder
-            .build_int_compare(binary_op.into(), lhs_elem, rhs_elem, "cmp");
-
+            .build_int_compare(IntPredicate::EQ, lhs_char, rhs_char, "cmp");
+        let char_eq_block = ctx.llvm_context.append_basic_block(ctx.func, "cmp_br");
         ctx.builder
-            .build_conditional_branch(cmp_res, next_block, ctx.func_exit);
+            .build_conditional_branch(cmp_res, char_eq_block, not_equal_block);
+        ctx.set_current_block(char_eq_block);
 
+        // both strings end here
+        let is_end = ctx.builder.build_int_compare(
+            IntPredicate::EQ,
+            lhs_char,
+            lhs_char.get_type().const_zero(),
+            "is_end",
+        );
+        let next_block = ctx.llvm_context.append_basic_block(ctx.func, "cmp_next");
+        ctx.builder
+            .build_conditional_branch(is_end, equal_block, next_block);
         ctx.set_current_block(next_block);
     }
 
-    ExprValue {
-        value: i64_ty.const_int(1, false).as_any_value_enum(),
-        ty: ExprType::Number,
+    // all characters of both buffers are equal
+    ctx.builder.build_unconditional_branch(equal_block);
+
+    let (true_block, fail_block) = if binary_op == FormulaOp::NotEq {
+        (not_equal_block, equal_block)
+    } else {
+        (equal_block, not_equal_block)
+    };
+
+    ctx.set_current_block(fail_block);
+    ctx.builder.build_unconditional_branch(false_block);
+
+    ctx.set_current_block(true_block);
+}
+
+/// Generates a comparison of two integer values.
+/// Branches to `false_block` if the comparison result is false.
+fn generate_int_cmp<'a>(
+    ctx: &mut CodegenCtx<'a>,
+    binary_op: FormulaOp,
+    lhs: ExprValue<'a>,
+    rhs: ExprValue<'a>,
+    false_block: BasicBlock<'a>,
+) -> Result<(), CodegenError> {
+    // use a signed comparison if any of the operands can be negative
+    let signed = lhs.ty == ExprType::SignedNumber || rhs.ty == ExprType::SignedNumber;
+
+    let predicate = binary_op.int_predicate(signed).ok_or_else(|| {
+        CodegenError::Other(format!(
+            "invalid comparison operation for numbers: {:?}",
+            binary_op
+        ))
+    })?;
+
+    let lhs_val = widen_int(
+        ctx,
+        lhs.value.into_int_value(),
+        lhs.ty == ExprType::SignedNumber,
+    );
+    let rhs_val = widen_int(
+        ctx,
+        rhs.value.into_int_value(),
+        rhs.ty == ExprType::SignedNumber,
+    );
+
+    let cmp_res = ctx
+        .builder
+        .build_int_compare(predicate, lhs_val, rhs_val, "cmp");
+
+    let next_block = ctx.llvm_context.append_basic_block(ctx.func, "cmp_br");
+    ctx.builder
+        .build_conditional_branch(cmp_res, next_block, false_block);
+
+    ctx.set_current_block(next_block);
+
+    Ok(())
+}
+
+/// Generates an arithmetic or a bitwise operation on two integer values.
+fn generate_arith<'a>(
+    ctx: &mut CodegenCtx<'a>,
+    binary_op: FormulaOp,
+    lhs: ExprValue<'a>,
+    rhs: ExprValue<'a>,
+) -> Result<ExprValue<'a>, CodegenError> {
+    if !lhs.ty.is_numeric() || !rhs.ty.is_numeric() {
+        return Err(CodegenError::Other(format!(
+            "invalid operands for {:?}: {:?} and {:?}",
+            binary_op, lhs.ty, rhs.ty
+        )));
+    }
+
+    let signed = lhs.ty == ExprType::SignedNumber || rhs.ty == ExprType::SignedNumber;
+
+    let lhs_val = widen_int(
+        ctx,
+        lhs.value.into_int_value(),
+        lhs.ty == ExprType::SignedNumber,
+    );
+    let rhs_val = widen_int(
+        ctx,
+        rhs.value.into_int_value(),
+        rhs.ty == ExprType::SignedNumber,
+    );
+
+    // shifting by more than the bit width is undefined, so we mask the shift amount
+    let shift_mask = ctx.llvm_context.i64_type().const_int(63, false);
+
+    let value = match binary_op {
+        FormulaOp::Add => ctx.builder.build_int_add(lhs_val, rhs_val, "add"),
+        FormulaOp::Sub => ctx.builder.build_int_sub(lhs_val, rhs_val, "sub"),
+        FormulaOp::Mul => ctx.builder.build_int_mul(lhs_val, rhs_val, "mul"),
+        FormulaOp::Div | FormulaOp::Mod => generate_div(ctx, binary_op, lhs_val, rhs_val, signed),
+        FormulaOp::BitAnd => ctx.builder.build_and(lhs_val, rhs_val, "and"),
+        FormulaOp::BitOr => ctx.builder.build_or(lhs_val, rhs_val, "or"),
+        FormulaOp::Shl => {
+            let shift = ctx.builder.build_and(rhs_val, shift_mask, "shift");
+            ctx.builder.build_left_shift(lhs_val, shift, "shl")
+        }
+        FormulaOp::Shr => {
+            let shift = ctx.builder.build_and(rhs_val, shift_mask, "shift");
+            ctx.builder.build_right_shift(lhs_val, shift, signed, "shr")
+        }
+        _ => {
+            return Err(CodegenError::Other(format!(
+                "unsupported operator: {:?}",
+                binary_op
+            )))
+        }
+    };
+
+    Ok(ExprValue {
+        value: value.as_any_value_enum(),
+        ty: if signed {
+            ExprType::SignedNumber
+        } else {
+            ExprType::Number
+        },
+    })
+}
+
+/// Generates an integer division or a remainder operation.
+/// Division by zero results in 0 instead of undefined behaviour. Signed operations are
+/// performed on absolute values because BPF doesn't have signed division instructions.
+fn generate_div<'a>(
+    ctx: &mut CodegenCtx<'a>,
+    binary_op: FormulaOp,
+    lhs: IntValue<'a>,
+    rhs: IntValue<'a>,
+    signed: bool,
+) -> IntValue<'a> {
+    let i64_ty = ctx.llvm_context.i64_type();
+
+    let (lhs_abs, lhs_neg) = if signed {
+        generate_int_abs(ctx, lhs)
+    } else {
+        (lhs, ctx.llvm_context.bool_type().const_zero())
+    };
+    let (rhs_abs, rhs_neg) = if signed {
+        generate_int_abs(ctx, rhs)
+    } else {
+        (rhs, ctx.llvm_context.bool_type().const_zero())
+    };
+
+    // replace a zero divisor with 1 and discard the result afterwards
+    let is_zero =
+        ctx.builder
+            .build_int_compare(IntPredicate::EQ, rhs_abs, i64_ty.const_zero(), "is_zero");
+    let divisor = ctx
+        .builder
+        .build_select(is_zero, i64_ty.const_int(1, false), rhs_abs, "divisor")
+        .into_int_value();
+
+    let (res, res_neg) = if binary_op == FormulaOp::Div {
+        (
+            ctx.builder.build_int_unsigned_div(lhs_abs, divisor, "div"),
+            ctx.builder.build_xor(lhs_neg, rhs_neg, "div_neg"),
+        )
+    } else {
+        // remainder has the sign of the dividend
+        (
+            ctx.builder.build_int_unsigned_rem(lhs_abs, divisor, "rem"),
+            lhs_neg,
+        )
+    };
+
+    let res = if signed {
+        let neg_res = ctx.builder.build_int_neg(res, "neg_res");
+        ctx.builder
+            .build_select(res_neg, neg_res, res, "signed_res")
+            .into_int_value()
+    } else {
+        res
+    };
+
+    ctx.builder
+        .build_select(is_zero, i64_ty.const_zero(), res, "div_res")
+        .into_int_value()
+}
+
+/// Returns an absolute value of a signed integer along with its sign.
+fn generate_int_abs<'a>(
+    ctx: &mut CodegenCtx<'a>,
+    value: IntValue<'a>,
+) -> (IntValue<'a>, IntValue<'a>) {
+    let is_neg = ctx.builder.build_int_compare(
+        IntPredicate::SLT,
+        value,
+        value.get_type().const_zero(),
+        "is_neg",
+    );
+    let neg_value = ctx.builder.build_int_neg(value, "neg");
+    let abs = ctx
+        .builder
+        .build_select(is_neg, neg_value, value, "abs")
+        .into_int_value();
+
+    (abs, is_neg)
+}
+
+/// Extends an integer value to 64 bits so that it can be used along with other numbers.
+pub fn widen_int<'a>(ctx: &mut CodegenCtx<'a>, value: IntValue<'a>, signed: bool) -> IntValue<'a> {
+    let i64_ty = ctx.llvm_context.i64_type();
+
+    if value.get_type().get_bit_width() >= i64_ty.get_bit_width() {
+        value
+    } else if signed {
+        ctx.builder.build_int_s_extend(value, i64_ty, "sext")
+    } else {
+        ctx.builder.build_int_z_extend(value, i64_ty, "zext")
     }
 }
@@ -0,0 +1,168 @@
+//! Human-readable formula errors that can be displayed next to the offending node property.
+
+use pest::error::{Error as PestError, InputLocation};
+use serde::Serialize;
+
+use super::{FormulaError, Rule, Span};
+use crate::dsl::NodeId;
+
+/// Error found in a node property formula.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Diagnostic {
+    pub node_id: NodeId,
+    /// Property key the formula belongs to; empty for errors about the whole node.
+    pub property: String,
+    /// Byte range of the offending part of the formula.
+    pub span: Span,
+    pub message: String,
+    /// Optional suggestion on how to fix the error.
+    pub hint: Option<String>,
+}
+
+impl Diagnostic {
+    pub fn new(node_id: NodeId, property: &str, span: Span, message: String) -> Self {
+        Self {
+            node_id,
+            property: property.to_string(),
+            span,
+            message,
+            hint: None,
+        }
+    }
+
+    pub fn with_hint(mut self, hint: &str) -> Self {
+        self.hint = Some(hint.to_string());
+        self
+    }
+
+    fn from_parse_error(
+        node_id: NodeId,
+        property: &str,
+        source: &str,
+        error: PestError<Rule>,
+    ) -> Self {
+        let span = match error.location {
+            // underline a single character, or the end of the formula if the input is incomplete
+            InputLocation::Pos(pos) => Span {
+                start: pos.min(source.len()),
+                end: (pos + 1).min(source.len()),
+            },
+            InputLocation::Span((start, end)) => Span { start, end },
+        };
+
+        let hint = match &error.variant {
+            pest::error::ErrorVariant::ParsingError { positives, .. }
+                if positives.contains(&Rule::EOI) =>
+            {
+                Some("use an operator to join multiple values")
+            }
+            _ => None,
+        };
+
+        let error = error.renamed_rules(rule_name);
+        let diagnostic = Self::new(
+            node_id,
+            property,
+            span,
+            error.variant.message().into_owned(),
+        );
+
+        match hint {
+            Some(hint) => diagnostic.with_hint(hint),
+            None => diagnostic,
+        }
+    }
+}
+
+impl FormulaError {
+    /// Converts the error into a list of diagnostics for the formula `source`.
+    pub fn into_diagnostics(
+        self,
+        node_id: NodeId,
+        property: &str,
+        source: &str,
+    ) -> Vec<Diagnostic> {
+        let whole_formula = Span {
+            start: 0,
+            end: source.len(),
+        };
+
+        match self {
+            FormulaError::Empty => vec![Diagnostic::new(
+                node_id,
+                property,
+                whole_formula,
+                "formula is empty".to_string(),
+            )],
+            FormulaError::ParseError(error) => vec![Diagnostic::from_parse_error(
+                node_id, property, source, error,
+            )],
+            FormulaError::Diagnostics(diagnostics) => diagnostics,
+            FormulaError::Other(message) => {
+                vec![Diagnostic::new(node_id, property, whole_formula, message)]
+            }
+        }
+    }
+}
+
+/// Returns a user-facing name of a grammar rule.
+fn rule_name(rule: &Rule) -> String {
+    match rule {
+        Rule::EOI => "end of formula",
+        Rule::ident => "identifier",
+        Rule::property => "property",
+        Rule::op_in => "`in`",
+        Rule::op_not_in => "`not in`",
+        Rule::op_and => "`&&`",
+        Rule::op_or => "`||`",
+        Rule::op_eq => "`=`",
+        Rule::op_not_eq => "`!=`",
+        Rule::op_shl => "`<<`",
+        Rule::op_shr => "`>>`",
+        Rule::op_gt_eq => "`>=`",
+        Rule::op_lt_eq => "`<=`",
+        Rule::op_lt => "`<`",
+        Rule::op_gt => "`>`",
+        Rule::op_add => "`+`",
+        Rule::op_sub => "`-`",
+        Rule::op_mul => "`*`",
+        Rule::op_div => "`/`",
+        Rule::op_mod => "`%`",
+        Rule::op_bit_and => "`&`",
+        Rule::op_bit_or => "`|`",
+        Rule::op_not => "`!`",
+        Rule::op_neg => "`-`",
+        Rule::op_by => "`by`",
+        Rule::literal_string | Rule::str_inner | Rule::str_char => "string",
+        Rule::literal_number | Rule::literal_float => "number",
+        Rule::literal_bool => "boolean",
+        Rule::literal_value => "value",
+        Rule::count => "`count`",
+        Rule::call => "function call",
+        Rule::list => "list",
+        Rule::term | Rule::expression => "expression",
+        rule => return format!("{:?}", rule),
+    }
+    .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::formulas::parse_formula;
+
+    #[test]
+    fn test_parse_error_diagnostic() {
+        let source = "input.pid 1";
+        let diagnostics = parse_formula(source)
+            .unwrap_err()
+            .into_diagnostics(1, "value", source);
+
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].property, "value");
+        assert_eq!(
+            (diagnostics[0].span.start, diagnostics[0].span.end),
+            (10, 11)
+        );
+        assert!(diagnostics[0].hint.is_some());
+    }
+}
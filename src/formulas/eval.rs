@@ -0,0 +1,299 @@
+//! Formula interpreter.
+//!
+//! Evaluates constant formulas on the host, e.g. node properties that are needed
+//! at the program load time.
+
+use std::{cmp::Ordering, fmt};
+
+use super::{Builtin, ExprType, FormulaError, FormulaExpr, FormulaOp, FormulaTerm, UnaryOp};
+
+/// Value of an evaluated formula.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Value {
+    String(String),
+    Number(u64),
+    SignedNumber(i64),
+    Boolean(bool),
+}
+
+impl Value {
+    pub fn ty(&self) -> ExprType {
+        match self {
+            Value::String(_) => ExprType::String,
+            Value::Number(_) => ExprType::Number,
+            Value::SignedNumber(_) => ExprType::SignedNumber,
+            Value::Boolean(_) => ExprType::Boolean,
+        }
+    }
+
+    pub fn into_string(self) -> Option<String> {
+        match self {
+            Value::String(str) => Some(str),
+            _ => None,
+        }
+    }
+
+    /// Returns the number bits; signed numbers are represented in two's complement.
+    fn as_bits(&self) -> Option<u64> {
+        match self {
+            Value::Number(num) => Some(*num),
+            Value::SignedNumber(num) => Some(*num as u64),
+            _ => None,
+        }
+    }
+
+    /// Numbers are true if they're non-zero.
+    fn is_true(&self) -> Result<bool, FormulaError> {
+        match self {
+            Value::Boolean(val) => Ok(*val),
+            Value::Number(num) => Ok(*num != 0),
+            Value::SignedNumber(num) => Ok(*num != 0),
+            Value::String(_) => Err(FormulaError::Other(
+                "expected a boolean condition, found a string".to_string(),
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::String(str) => f.write_str(str),
+            Value::Number(num) => write!(f, "{}", num),
+            Value::SignedNumber(num) => write!(f, "{}", num),
+            Value::Boolean(val) => write!(f, "{}", val),
+        }
+    }
+}
+
+/// Evaluates a constant formula.
+/// Fails if the formula refers to values that are known only at runtime, like `input` properties.
+pub fn eval(expr: &FormulaExpr) -> Result<Value, FormulaError> {
+    match expr {
+        FormulaExpr::Term(term, _) => eval_term(term),
+        FormulaExpr::Binary {
+            lhs,
+            rhs,
+            binary_op: FormulaOp::And,
+            ..
+        } => Ok(Value::Boolean(
+            eval(lhs)?.is_true()? && eval(rhs)?.is_true()?,
+        )),
+        FormulaExpr::Binary {
+            lhs,
+            rhs,
+            binary_op: FormulaOp::Or,
+            ..
+        } => Ok(Value::Boolean(
+            eval(lhs)?.is_true()? || eval(rhs)?.is_true()?,
+        )),
+        FormulaExpr::Binary {
+            lhs,
+            rhs,
+            binary_op,
+            ..
+        } if binary_op.is_membership() => {
+            let found = eval_membership(eval(lhs)?, rhs)?;
+            Ok(Value::Boolean(found == (*binary_op == FormulaOp::In)))
+        }
+        FormulaExpr::Binary {
+            lhs,
+            rhs,
+            binary_op,
+            ..
+        } => eval_binary(*binary_op, eval(lhs)?, eval(rhs)?),
+        FormulaExpr::Unary { expr, unary_op, .. } => match (unary_op, eval(expr)?) {
+            (UnaryOp::Not, val) => Ok(Value::Boolean(!val.is_true()?)),
+            (UnaryOp::Neg, val) => match val.as_bits() {
+                Some(num) => Ok(Value::SignedNumber((num as i64).wrapping_neg())),
+                None => Err(FormulaError::Other(format!(
+                    "cannot apply `-` to a {}",
+                    val.ty()
+                ))),
+            },
+        },
+        FormulaExpr::Call { func, args, .. } => {
+            let args = args.iter().map(eval).collect::<Result<Vec<_>, _>>()?;
+            eval_call(func, args)
+        }
+        FormulaExpr::List(..) => Err(FormulaError::Other(
+            "lists can be used only with `in` and `not in`".to_string(),
+        )),
+        FormulaExpr::GroupBy { .. } => Err(FormulaError::Other(
+            "aggregates are not constants".to_string(),
+        )),
+    }
+}
+
+fn eval_term(term: &FormulaTerm) -> Result<Value, FormulaError> {
+    match term {
+        FormulaTerm::String(str) => Ok(Value::String(str.clone())),
+        FormulaTerm::Number(num) => Ok(Value::Number(*num)),
+        FormulaTerm::SignedNumber(num) => Ok(Value::SignedNumber(*num)),
+        FormulaTerm::Boolean(val) => Ok(Value::Boolean(*val)),
+        FormulaTerm::Property(object, prop_name) => Err(FormulaError::Other(format!(
+            "`{}.{}` is not a constant",
+            object, prop_name
+        ))),
+        FormulaTerm::CountCall(_) => Err(FormulaError::Other(
+            "aggregates are not constants".to_string(),
+        )),
+        FormulaTerm::Float(_) => Err(FormulaError::Other(
+            "fractional numbers can be used only as a percentile rank".to_string(),
+        )),
+    }
+}
+
+fn eval_call(func: &str, args: Vec<Value>) -> Result<Value, FormulaError> {
+    match (func, args.as_slice()) {
+        ("env", [Value::String(var_name)]) => {
+            std::env::var(var_name).map(Value::String).map_err(|_| {
+                FormulaError::Other(format!("environment variable {} is not set", var_name))
+            })
+        }
+        ("len", [Value::String(str)]) => Ok(Value::Number(str.len() as u64)),
+        (func, _) if Builtin::lookup(func).is_some_and(|builtin| builtin.runtime_only) => Err(
+            FormulaError::Other(format!("`{}()` is known only at runtime", func)),
+        ),
+        (func, _) => Err(FormulaError::Other(format!(
+            "invalid call to function `{}`",
+            func
+        ))),
+    }
+}
+
+fn eval_membership(lhs: Value, rhs: &FormulaExpr) -> Result<bool, FormulaError> {
+    match rhs {
+        FormulaExpr::List(elems, _) => {
+            for elem in elems {
+                if eval_binary(FormulaOp::Eq, lhs.clone(), eval(elem)?)? == Value::Boolean(true) {
+                    return Ok(true);
+                }
+            }
+            Ok(false)
+        }
+        rhs => match (lhs, eval(rhs)?) {
+            // searching for a substring
+            (Value::String(needle), Value::String(haystack)) => Ok(haystack.contains(&needle)),
+            (lhs, rhs) => Err(FormulaError::Other(format!(
+                "cannot look for a {} in a {}",
+                lhs.ty(),
+                rhs.ty()
+            ))),
+        },
+    }
+}
+
+/// Evaluates comparisons and arithmetic operators, following the BPF codegen semantics.
+fn eval_binary(binary_op: FormulaOp, lhs: Value, rhs: Value) -> Result<Value, FormulaError> {
+    let signed = matches!(lhs, Value::SignedNumber(_)) || matches!(rhs, Value::SignedNumber(_));
+
+    let (lhs_bits, rhs_bits) = match (lhs.as_bits(), rhs.as_bits()) {
+        (Some(lhs_bits), Some(rhs_bits)) => (lhs_bits, rhs_bits),
+        _ => {
+            return match (binary_op, lhs, rhs) {
+                (FormulaOp::Add, Value::String(lhs), Value::String(rhs)) => {
+                    Ok(Value::String(lhs + &rhs))
+                }
+                (FormulaOp::Eq, lhs, rhs) if lhs.ty() == rhs.ty() => Ok(Value::Boolean(lhs == rhs)),
+                (FormulaOp::NotEq, lhs, rhs) if lhs.ty() == rhs.ty() => {
+                    Ok(Value::Boolean(lhs != rhs))
+                }
+                (binary_op, lhs, rhs) => Err(FormulaError::Other(format!(
+                    "cannot apply `{}` to a {} and a {}",
+                    binary_op,
+                    lhs.ty(),
+                    rhs.ty()
+                ))),
+            };
+        }
+    };
+
+    if binary_op.is_comparison() {
+        let ordering = if signed {
+            (lhs_bits as i64).cmp(&(rhs_bits as i64))
+        } else {
+            lhs_bits.cmp(&rhs_bits)
+        };
+
+        let res = match binary_op {
+            FormulaOp::Lt => ordering == Ordering::Less,
+            FormulaOp::LtEq => ordering != Ordering::Greater,
+            FormulaOp::Gt => ordering == Ordering::Greater,
+            FormulaOp::GtEq => ordering != Ordering::Less,
+            FormulaOp::Eq => ordering == Ordering::Equal,
+            _ => ordering != Ordering::Equal,
+        };
+        return Ok(Value::Boolean(res));
+    }
+
+    // shifting by more than the bit width is masked, same as in BPF code
+    let shift = rhs_bits & 63;
+
+    let res = match binary_op {
+        FormulaOp::Add => lhs_bits.wrapping_add(rhs_bits),
+        FormulaOp::Sub => lhs_bits.wrapping_sub(rhs_bits),
+        FormulaOp::Mul => lhs_bits.wrapping_mul(rhs_bits),
+        // division by zero results in 0
+        FormulaOp::Div | FormulaOp::Mod if rhs_bits == 0 => 0,
+        FormulaOp::Div if signed => (lhs_bits as i64).wrapping_div(rhs_bits as i64) as u64,
+        FormulaOp::Div => lhs_bits / rhs_bits,
+        FormulaOp::Mod if signed => (lhs_bits as i64).wrapping_rem(rhs_bits as i64) as u64,
+        FormulaOp::Mod => lhs_bits % rhs_bits,
+        FormulaOp::BitAnd => lhs_bits & rhs_bits,
+        FormulaOp::BitOr => lhs_bits | rhs_bits,
+        FormulaOp::Shl => lhs_bits << shift,
+        FormulaOp::Shr if signed => ((lhs_bits as i64) >> shift) as u64,
+        FormulaOp::Shr => lhs_bits >> shift,
+        binary_op => {
+            return Err(FormulaError::Other(format!(
+                "unsupported operator: {}",
+                binary_op
+            )))
+        }
+    };
+
+    Ok(if signed {
+        Value::SignedNumber(res as i64)
+    } else {
+        Value::Number(res)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{eval, Value};
+    use crate::formulas::parse_formula;
+
+    fn eval_str(formula: &str) -> Value {
+        eval(&parse_formula(formula).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_eval_constants() {
+        assert_eq!(
+            eval_str(r#""/usr/lib/" + "libssl.so.3""#),
+            Value::String("/usr/lib/libssl.so.3".to_string())
+        );
+        assert_eq!(eval_str("(1 + 2) * 3 % 5"), Value::Number(4));
+        assert_eq!(eval_str("-7 / 2"), Value::SignedNumber(-3));
+        assert_eq!(eval_str("10 / 0"), Value::Number(0));
+        assert_eq!(eval_str("-1 < 1 && 3 in [1, 2, 3]"), Value::Boolean(true));
+        assert_eq!(eval_str(r#""ssl" not in "libssl""#), Value::Boolean(false));
+        assert_eq!(eval_str(r#"len("libssl")"#), Value::Number(6));
+        assert_eq!(eval_str("true && !false"), Value::Boolean(true));
+        assert_eq!(eval_str("false || 1 > 2"), Value::Boolean(false));
+    }
+
+    #[test]
+    fn test_eval_env() {
+        std::env::set_var("METALENS_TEST_LIB", "/opt/lib");
+
+        assert_eq!(
+            eval_str(r#"env("METALENS_TEST_LIB") + "/libssl.so.3""#),
+            Value::String("/opt/lib/libssl.so.3".to_string())
+        );
+        assert!(eval(&parse_formula("input.pid + 1").unwrap()).is_err());
+        assert!(eval(&parse_formula("nstime()").unwrap()).is_err());
+    }
+}
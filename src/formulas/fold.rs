@@ -0,0 +1,175 @@
+//! Constant folding.
+//!
+//! Evaluates constant subexpressions at compile time to reduce the number of instructions
+//! in the generated BPF code.
+
+use super::{eval, FormulaExpr, FormulaOp, FormulaTerm, Span, UnaryOp, Value};
+
+/// Replaces constant subexpressions with literals.
+/// Subexpressions that fail to evaluate are kept as is, so that errors are reported by codegen.
+pub fn fold_constants(expr: &FormulaExpr) -> FormulaExpr {
+    match expr {
+        FormulaExpr::Term(..) => expr.clone(),
+        FormulaExpr::Binary {
+            lhs,
+            rhs,
+            binary_op,
+            span,
+        } => {
+            let lhs = fold_constants(lhs);
+            let rhs = fold_constants(rhs);
+
+            if let Some(folded) = fold_logical(*binary_op, &lhs, &rhs) {
+                return folded;
+            }
+
+            fold_expr(FormulaExpr::Binary {
+                lhs: Box::new(lhs),
+                rhs: Box::new(rhs),
+                binary_op: *binary_op,
+                span: *span,
+            })
+        }
+        FormulaExpr::Unary {
+            expr,
+            unary_op,
+            span,
+        } => fold_expr(FormulaExpr::Unary {
+            expr: Box::new(fold_constants(expr)),
+            unary_op: *unary_op,
+            span: *span,
+        }),
+        FormulaExpr::Call { func, args, span } => fold_expr(FormulaExpr::Call {
+            func: func.clone(),
+            args: args.iter().map(fold_constants).collect(),
+            span: *span,
+        }),
+        // lists are not values on their own, but their elements can be folded
+        FormulaExpr::List(elems, span) => {
+            FormulaExpr::List(elems.iter().map(fold_constants).collect(), *span)
+        }
+        FormulaExpr::GroupBy { expr, key, span } => FormulaExpr::GroupBy {
+            expr: Box::new(fold_constants(expr)),
+            key: Box::new(fold_constants(key)),
+            span: *span,
+        },
+    }
+}
+
+/// Returns the boolean value of a constant condition, or `None` if it's known only at runtime.
+pub fn const_condition(expr: &FormulaExpr) -> Option<bool> {
+    match expr.term()? {
+        FormulaTerm::Boolean(val) => Some(*val),
+        // numbers are true if they're non-zero
+        FormulaTerm::Number(num) => Some(*num != 0),
+        FormulaTerm::SignedNumber(num) => Some(*num != 0),
+        _ => None,
+    }
+}
+
+/// Replaces an expression with a literal if all of its operands are literals.
+fn fold_expr(expr: FormulaExpr) -> FormulaExpr {
+    let operands_const = match &expr {
+        FormulaExpr::Binary { lhs, rhs, .. } => is_literal(lhs) && is_literal(rhs),
+        FormulaExpr::Unary { expr, .. } => is_literal(expr),
+        FormulaExpr::Call { args, .. } => args.iter().all(is_literal),
+        _ => false,
+    };
+
+    if !operands_const {
+        return expr;
+    }
+
+    let term = match eval(&expr) {
+        Ok(Value::String(str)) => FormulaTerm::String(str),
+        Ok(Value::Number(num)) => FormulaTerm::Number(num),
+        Ok(Value::SignedNumber(num)) => FormulaTerm::SignedNumber(num),
+        Ok(Value::Boolean(val)) => FormulaTerm::Boolean(val),
+        Err(_) => return expr,
+    };
+
+    FormulaExpr::Term(term, expr.span())
+}
+
+/// Simplifies logical operators with a constant operand, e.g. `x && false` becomes `false`.
+fn fold_logical(binary_op: FormulaOp, lhs: &FormulaExpr, rhs: &FormulaExpr) -> Option<FormulaExpr> {
+    let (const_val, other) = match (const_condition(lhs), const_condition(rhs)) {
+        // both operands are constant, evaluate the whole expression
+        (Some(_), Some(_)) => return None,
+        (Some(val), None) => (val, rhs),
+        (None, Some(val)) => (val, lhs),
+        (None, None) => return None,
+    };
+
+    let span = Span {
+        start: lhs.span().start,
+        end: rhs.span().end,
+    };
+
+    match (binary_op, const_val) {
+        // formulas have no side effects, so the other operand can be dropped
+        (FormulaOp::And, false) => Some(FormulaExpr::Term(FormulaTerm::Boolean(false), span)),
+        (FormulaOp::Or, true) => Some(FormulaExpr::Term(FormulaTerm::Boolean(true), span)),
+        // keep the operator if it converts a number into a boolean
+        (FormulaOp::And, true) | (FormulaOp::Or, false) if is_boolean(other) => Some(other.clone()),
+        _ => None,
+    }
+}
+
+fn is_boolean(expr: &FormulaExpr) -> bool {
+    match expr {
+        FormulaExpr::Binary { binary_op, .. } => {
+            binary_op.is_comparison() || binary_op.is_logical() || binary_op.is_membership()
+        }
+        FormulaExpr::Unary { unary_op, .. } => *unary_op == UnaryOp::Not,
+        FormulaExpr::Term(FormulaTerm::Boolean(_), _) => true,
+        _ => false,
+    }
+}
+
+fn is_literal(expr: &FormulaExpr) -> bool {
+    expr.term()
+        .is_some_and(|term| !matches!(term, FormulaTerm::Property(..) | FormulaTerm::CountCall(_)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{const_condition, fold_constants};
+    use crate::formulas::{parse_formula, FormulaExpr};
+
+    fn fold_str(formula: &str) -> FormulaExpr {
+        fold_constants(&parse_formula(formula).unwrap()).strip_spans()
+    }
+
+    #[test]
+    fn test_fold_constants() {
+        assert_eq!(
+            fold_str("input.pid > 10 * 10 + 1"),
+            parse_formula("input.pid > 101").unwrap().strip_spans()
+        );
+        assert_eq!(
+            fold_str(r#"input.pid in [1 + 1, 3] && "a" = "a""#),
+            parse_formula("input.pid in [2, 3]").unwrap().strip_spans()
+        );
+        assert_eq!(const_condition(&fold_str(r#""bash" = "sh""#)), Some(false));
+        assert_eq!(
+            const_condition(&fold_str("input.pid = 1 && 1 > 2")),
+            Some(false)
+        );
+        assert_eq!(const_condition(&fold_str("input.pid = 1 || 2")), Some(true));
+        assert_eq!(const_condition(&fold_str("input.pid = 1")), None);
+
+        assert_eq!(
+            const_condition(&fold_str("input.pid = 1 && false")),
+            Some(false)
+        );
+        assert_eq!(
+            const_condition(&fold_str("true || input.pid = 1")),
+            Some(true)
+        );
+        assert_eq!(
+            fold_str("input.pid = 1 && true"),
+            parse_formula("input.pid = 1").unwrap().strip_spans()
+        );
+    }
+}
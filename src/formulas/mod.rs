@@ -2,17 +2,51 @@
 
 // TODO: should be split into its own separate crate.
 
+mod aggregate;
+mod builtins;
 mod codegen;
+mod diagnostic;
+mod eval;
+mod fold;
 mod parser;
+mod typeck;
 
+use std::fmt;
+
+pub use aggregate::*;
+pub use builtins::*;
 pub use codegen::*;
+pub use diagnostic::*;
+pub use eval::*;
+pub use fold::*;
 pub use parser::*;
+pub use typeck::*;
 
 #[derive(PartialEq, Eq, Hash, Clone, Copy, Debug)]
 pub enum ExprType {
     String,
     Number,
+    SignedNumber,
     Boolean,
     // FIXME: ugly hack
     Counter,
 }
+
+impl ExprType {
+    /// Returns true if the type is an integer number.
+    pub fn is_numeric(&self) -> bool {
+        matches!(self, ExprType::Number | ExprType::SignedNumber)
+    }
+}
+
+impl fmt::Display for ExprType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ExprType::String => "string",
+            ExprType::Number => "number",
+            ExprType::SignedNumber => "signed number",
+            ExprType::Boolean => "boolean",
+            ExprType::Counter => "aggregate",
+        })
+    }
+}
@@ -1,10 +1,18 @@
 //! Formulas parser.
 
+use std::fmt;
+
 use inkwell::IntPredicate;
-use pest::iterators::Pair;
+use pest::error::ErrorVariant;
+use pest::iterators::{Pair, Pairs};
+use pest::pratt_parser::{Assoc, Op, PrattParser};
 use pest::Parser;
 use pest_derive::Parser;
 
+use serde::Serialize;
+
+use super::Diagnostic;
+
 #[derive(Parser)]
 #[grammar = "formulas/formulas.pest"]
 pub struct FormulasParser;
@@ -13,9 +21,26 @@ pub struct FormulasParser;
 pub enum FormulaError {
     Empty,
     ParseError(pest::error::Error<Rule>),
+    Diagnostics(Vec<Diagnostic>),
     Other(String),
 }
 
+/// Byte range of an expression in the formula source.
+#[derive(Debug, Default, PartialEq, Eq, Hash, Clone, Copy, Serialize)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl From<pest::Span<'_>> for Span {
+    fn from(span: pest::Span) -> Self {
+        Span {
+            start: span.start(),
+            end: span.end(),
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
 pub enum FormulaOp {
     Lt,
@@ -25,22 +50,101 @@ pub enum FormulaOp {
     GtEq,
     Gt,
     Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    BitAnd,
+    BitOr,
+    Shl,
+    Shr,
+    In,
+    NotIn,
     And,
     Or,
 }
 
-impl Into<IntPredicate> for FormulaOp {
-    fn into(self) -> IntPredicate {
-        match self {
-            FormulaOp::Eq => IntPredicate::EQ,
-            FormulaOp::NotEq => IntPredicate::NE,
-            FormulaOp::Lt => IntPredicate::ULT,
-            FormulaOp::Gt => IntPredicate::UGT,
-            FormulaOp::LtEq => IntPredicate::ULE,
-            FormulaOp::GtEq => IntPredicate::UGE,
-            // TODO: fixme
-            _ => panic!("invalid op conversion: {:?}", self),
-        }
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub enum UnaryOp {
+    Not,
+    Neg,
+}
+
+impl fmt::Display for FormulaOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            FormulaOp::Lt => "<",
+            FormulaOp::Eq => "=",
+            FormulaOp::NotEq => "!=",
+            FormulaOp::LtEq => "<=",
+            FormulaOp::GtEq => ">=",
+            FormulaOp::Gt => ">",
+            FormulaOp::Add => "+",
+            FormulaOp::Sub => "-",
+            FormulaOp::Mul => "*",
+            FormulaOp::Div => "/",
+            FormulaOp::Mod => "%",
+            FormulaOp::BitAnd => "&",
+            FormulaOp::BitOr => "|",
+            FormulaOp::Shl => "<<",
+            FormulaOp::Shr => ">>",
+            FormulaOp::In => "in",
+            FormulaOp::NotIn => "not in",
+            FormulaOp::And => "&&",
+            FormulaOp::Or => "||",
+        })
+    }
+}
+
+impl fmt::Display for UnaryOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            UnaryOp::Not => "!",
+            UnaryOp::Neg => "-",
+        })
+    }
+}
+
+impl FormulaOp {
+    /// Returns true if the operator compares two values.
+    pub fn is_comparison(self) -> bool {
+        matches!(
+            self,
+            FormulaOp::Lt
+                | FormulaOp::Eq
+                | FormulaOp::NotEq
+                | FormulaOp::LtEq
+                | FormulaOp::GtEq
+                | FormulaOp::Gt
+        )
+    }
+
+    /// Returns true if the operator checks for a set membership or a substring.
+    pub fn is_membership(self) -> bool {
+        matches!(self, FormulaOp::In | FormulaOp::NotIn)
+    }
+
+    /// Returns true if the operator is a logical conjunction or disjunction.
+    pub fn is_logical(self) -> bool {
+        matches!(self, FormulaOp::And | FormulaOp::Or)
+    }
+
+    /// Returns an integer comparison predicate for this operator.
+    /// Returns `None` if the operator is not a comparison.
+    pub fn int_predicate(self, signed: bool) -> Option<IntPredicate> {
+        Some(match (self, signed) {
+            (FormulaOp::Eq, _) => IntPredicate::EQ,
+            (FormulaOp::NotEq, _) => IntPredicate::NE,
+            (FormulaOp::Lt, false) => IntPredicate::ULT,
+            (FormulaOp::Lt, true) => IntPredicate::SLT,
+            (FormulaOp::Gt, false) => IntPredicate::UGT,
+            (FormulaOp::Gt, true) => IntPredicate::SGT,
+            (FormulaOp::LtEq, false) => IntPredicate::ULE,
+            (FormulaOp::LtEq, true) => IntPredicate::SLE,
+            (FormulaOp::GtEq, false) => IntPredicate::UGE,
+            (FormulaOp::GtEq, true) => IntPredicate::SGE,
+            _ => return None,
+        })
     }
 }
 
@@ -48,9 +152,13 @@ impl Into<IntPredicate> for FormulaOp {
 pub enum FormulaTerm {
     Property(String, String),
     Number(u64),
+    SignedNumber(i64),
     Boolean(bool),
     String(String),
     CountCall(String),
+    /// Fractional number, e.g. a percentile rank. Stored as `f64` bits so that terms can be
+    /// compared and hashed.
+    Float(u64),
 }
 
 impl FormulaTerm {
@@ -60,6 +168,13 @@ impl FormulaTerm {
             _ => None,
         }
     }
+
+    pub fn float_value(&self) -> Option<f64> {
+        match self {
+            FormulaTerm::Float(bits) => Some(f64::from_bits(*bits)),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Debug, PartialEq, Eq, Hash, Clone)]
@@ -68,33 +183,117 @@ pub enum FormulaExpr {
         lhs: Box<FormulaExpr>,
         rhs: Box<FormulaExpr>,
         binary_op: FormulaOp,
+        span: Span,
+    },
+    Unary {
+        expr: Box<FormulaExpr>,
+        unary_op: UnaryOp,
+        span: Span,
+    },
+    /// Function call, e.g. `env("HOME")`.
+    Call {
+        func: String,
+        args: Vec<FormulaExpr>,
+        span: Span,
+    },
+    List(Vec<FormulaExpr>, Span),
+    Term(FormulaTerm, Span),
+    /// Aggregate grouped by a key, e.g. `count(input) by input.pid`.
+    /// Can only be the top-level expression of a formula.
+    GroupBy {
+        expr: Box<FormulaExpr>,
+        key: Box<FormulaExpr>,
+        span: Span,
     },
-    Term(FormulaTerm),
 }
 
 impl FormulaExpr {
     pub fn term(&self) -> Option<&FormulaTerm> {
         match self {
-            FormulaExpr::Term(term) => Some(term),
+            FormulaExpr::Term(term, _) => Some(term),
             _ => None,
         }
     }
+
+    fn with_span(mut self, new_span: Span) -> Self {
+        match &mut self {
+            FormulaExpr::Binary { span, .. }
+            | FormulaExpr::Unary { span, .. }
+            | FormulaExpr::Call { span, .. }
+            | FormulaExpr::List(_, span)
+            | FormulaExpr::Term(_, span)
+            | FormulaExpr::GroupBy { span, .. } => *span = new_span,
+        }
+        self
+    }
+
+    /// Returns the location of this expression in the formula source.
+    pub fn span(&self) -> Span {
+        match self {
+            FormulaExpr::Binary { span, .. }
+            | FormulaExpr::Unary { span, .. }
+            | FormulaExpr::Call { span, .. }
+            | FormulaExpr::List(_, span)
+            | FormulaExpr::Term(_, span)
+            | FormulaExpr::GroupBy { span, .. } => *span,
+        }
+    }
+}
+
+#[cfg(test)]
+impl FormulaExpr {
+    /// Returns a copy of the expression with default spans, so that tests can compare
+    /// expressions by their structure.
+    pub fn strip_spans(&self) -> FormulaExpr {
+        let strip_all = |exprs: &[FormulaExpr]| exprs.iter().map(Self::strip_spans).collect();
+
+        match self {
+            FormulaExpr::Binary {
+                lhs,
+                rhs,
+                binary_op,
+                ..
+            } => FormulaExpr::Binary {
+                lhs: Box::new(lhs.strip_spans()),
+                rhs: Box::new(rhs.strip_spans()),
+                binary_op: *binary_op,
+                span: Span::default(),
+            },
+            FormulaExpr::Unary { expr, unary_op, .. } => FormulaExpr::Unary {
+                expr: Box::new(expr.strip_spans()),
+                unary_op: *unary_op,
+                span: Span::default(),
+            },
+            FormulaExpr::Call { func, args, .. } => FormulaExpr::Call {
+                func: func.clone(),
+                args: strip_all(args),
+                span: Span::default(),
+            },
+            FormulaExpr::List(items, _) => FormulaExpr::List(strip_all(items), Span::default()),
+            FormulaExpr::Term(term, _) => FormulaExpr::Term(term.clone(), Span::default()),
+            FormulaExpr::GroupBy { expr, key, .. } => FormulaExpr::GroupBy {
+                expr: Box::new(expr.strip_spans()),
+                key: Box::new(key.strip_spans()),
+                span: Span::default(),
+            },
+        }
+    }
 }
 
 impl From<&str> for FormulaExpr {
     fn from(str: &str) -> Self {
-        FormulaExpr::Term(FormulaTerm::String(str.to_string()))
+        FormulaExpr::Term(FormulaTerm::String(str.to_string()), Span::default())
     }
 }
 
 impl From<u64> for FormulaExpr {
     fn from(num: u64) -> Self {
-        FormulaExpr::Term(FormulaTerm::Number(num))
+        FormulaExpr::Term(FormulaTerm::Number(num), Span::default())
     }
 }
 
-fn parse_term(pair: Pair<Rule>) -> FormulaTerm {
-    match pair.as_rule() {
+fn parse_term(pair: Pair<Rule>) -> Result<FormulaTerm, FormulaError> {
+    let term = match pair.as_rule() {
         Rule::property => {
             let mut pair = pair.into_inner();
             let ident1 = pair.next().unwrap();
@@ -104,92 +303,209 @@ fn parse_term(pair: Pair<Rule>) -> FormulaTerm {
         Rule::literal_value => {
             let pair = pair.into_inner().next().unwrap();
             match pair.as_rule() {
-                Rule::literal_number => FormulaTerm::Number(pair.as_str().parse().unwrap()),
+                Rule::literal_number if pair.as_str().starts_with('-') => {
+                    FormulaTerm::SignedNumber(parse_int(&pair)?)
+                }
+                Rule::literal_number => FormulaTerm::Number(parse_int(&pair)?),
+                Rule::literal_float => {
+                    FormulaTerm::Float(pair.as_str().parse::<f64>().unwrap().to_bits())
+                }
                 Rule::literal_string => {
                     FormulaTerm::String(pair.into_inner().next().unwrap().as_str().to_string())
                 }
-                Rule::literal_bool => {
-                    FormulaTerm::Boolean(pair.into_inner().as_str().parse().unwrap())
-                }
-                _ => todo!(),
+                Rule::literal_bool => FormulaTerm::Boolean(pair.as_str() == "true"),
+                rule => unreachable!("unexpected literal: {:?}", rule),
             }
         }
         Rule::count => {
             let pair = pair.into_inner().next().unwrap();
             match pair.as_rule() {
                 Rule::ident => FormulaTerm::CountCall(pair.as_str().to_string()),
-                _ => todo!(),
+                rule => unreachable!("unexpected count argument: {:?}", rule),
             }
         }
-        _ => {
-            dbg!(&pair);
-            todo!();
-        }
-    }
+        rule => unreachable!("unexpected term: {:?}", rule),
+    };
+
+    Ok(term)
+}
+
+/// Parses an integer literal, reporting literals that don't fit into 64 bits.
+fn parse_int<T: std::str::FromStr>(pair: &Pair<Rule>) -> Result<T, FormulaError> {
+    pair.as_str().parse().map_err(|_| {
+        FormulaError::ParseError(pest::error::Error::new_from_span(
+            ErrorVariant::CustomError {
+                message: format!("number {} does not fit into 64 bits", pair.as_str()),
+            },
+            pair.as_span(),
+        ))
+    })
+}
+
+/// Builds a precedence climbing parser for binary operators.
+/// Operators are listed in the order of increasing precedence.
+fn pratt_parser() -> PrattParser<Rule> {
+    PrattParser::new()
+        .op(Op::infix(Rule::op_or, Assoc::Left))
+        .op(Op::infix(Rule::op_and, Assoc::Left))
+        .op(Op::infix(Rule::op_eq, Assoc::Left)
+            | Op::infix(Rule::op_not_eq, Assoc::Left)
+            | Op::infix(Rule::op_lt, Assoc::Left)
+            | Op::infix(Rule::op_gt, Assoc::Left)
+            | Op::infix(Rule::op_lt_eq, Assoc::Left)
+            | Op::infix(Rule::op_gt_eq, Assoc::Left)
+            | Op::infix(Rule::op_in, Assoc::Left)
+            | Op::infix(Rule::op_not_in, Assoc::Left))
+        .op(Op::infix(Rule::op_bit_or, Assoc::Left))
+        .op(Op::infix(Rule::op_bit_and, Assoc::Left))
+        .op(Op::infix(Rule::op_shl, Assoc::Left) | Op::infix(Rule::op_shr, Assoc::Left))
+        .op(Op::infix(Rule::op_add, Assoc::Left) | Op::infix(Rule::op_sub, Assoc::Left))
+        .op(Op::infix(Rule::op_mul, Assoc::Left)
+            | Op::infix(Rule::op_div, Assoc::Left)
+            | Op::infix(Rule::op_mod, Assoc::Left))
+        .op(Op::prefix(Rule::op_not) | Op::prefix(Rule::op_neg))
 }
 
-fn parse_expr(pair: Pair<Rule>) -> FormulaExpr {
+fn parse_binary_op(pair: Pair<Rule>) -> FormulaOp {
     match pair.as_rule() {
-        Rule::expression => {
-            let mut pair = pair.into_inner();
-            let lhs = parse_expr(pair.next().unwrap());
-
-            let next_op = pair.next();
-
-            if let Some(true) = next_op.as_ref().map(|p| p.as_rule() == Rule::binary_op) {
-                let rhs = parse_expr(pair.next().unwrap());
-
-                return FormulaExpr::Binary {
-                    lhs: Box::new(lhs),
-                    rhs: Box::new(rhs),
-                    binary_op: match next_op.unwrap().as_str() {
-                        "&&" => FormulaOp::And,
-                        "||" => FormulaOp::Or,
-                        ">=" => FormulaOp::GtEq,
-                        "<=" => FormulaOp::LtEq,
-                        "!=" => FormulaOp::NotEq,
-                        ">" => FormulaOp::Gt,
-                        "<" => FormulaOp::Lt,
-                        "=" => FormulaOp::Eq,
-                        "+" => FormulaOp::Add,
-                        _ => todo!(),
-                    },
-                };
-            } else {
-                lhs
-            }
-        }
-        Rule::term => {
-            let term = pair.into_inner().next().unwrap();
-            if term.as_rule() == Rule::expression {
-                parse_expr(term)
-            } else {
-                FormulaExpr::Term(parse_term(term))
-            }
-        }
-        _ => todo!(),
+        Rule::op_and => FormulaOp::And,
+        Rule::op_or => FormulaOp::Or,
+        Rule::op_gt_eq => FormulaOp::GtEq,
+        Rule::op_lt_eq => FormulaOp::LtEq,
+        Rule::op_not_eq => FormulaOp::NotEq,
+        Rule::op_gt => FormulaOp::Gt,
+        Rule::op_lt => FormulaOp::Lt,
+        Rule::op_eq => FormulaOp::Eq,
+        Rule::op_add => FormulaOp::Add,
+        Rule::op_sub => FormulaOp::Sub,
+        Rule::op_mul => FormulaOp::Mul,
+        Rule::op_div => FormulaOp::Div,
+        Rule::op_mod => FormulaOp::Mod,
+        Rule::op_bit_and => FormulaOp::BitAnd,
+        Rule::op_bit_or => FormulaOp::BitOr,
+        Rule::op_shl => FormulaOp::Shl,
+        Rule::op_shr => FormulaOp::Shr,
+        Rule::op_in => FormulaOp::In,
+        Rule::op_not_in => FormulaOp::NotIn,
+        rule => unreachable!("unexpected binary operator: {:?}", rule),
     }
 }
 
+fn parse_expr(pairs: Pairs<Rule>, pratt: &PrattParser<Rule>) -> Result<FormulaExpr, FormulaError> {
+    pratt
+        .map_primary(|primary| match primary.as_rule() {
+            Rule::term => {
+                let span = Span::from(primary.as_span());
+                let term = primary.into_inner().next().unwrap();
+                Ok(match term.as_rule() {
+                    // include parentheses into the span
+                    Rule::expression => parse_expr(term.into_inner(), pratt)?.with_span(span),
+                    Rule::list => FormulaExpr::List(
+                        term.into_inner()
+                            .map(|elem| parse_expr(elem.into_inner(), pratt))
+                            .collect::<Result<_, _>>()?,
+                        span,
+                    ),
+                    Rule::call => {
+                        let mut pairs = term.into_inner();
+                        let func = pairs.next().unwrap().as_str().to_string();
+                        FormulaExpr::Call {
+                            func,
+                            args: pairs
+                                .map(|arg| parse_expr(arg.into_inner(), pratt))
+                                .collect::<Result<_, _>>()?,
+                            span,
+                        }
+                    }
+                    _ => FormulaExpr::Term(parse_term(term)?, span),
+                })
+            }
+            rule => unreachable!("unexpected expression: {:?}", rule),
+        })
+        .map_infix(|lhs, op, rhs| {
+            let (lhs, rhs) = (lhs?, rhs?);
+            Ok(FormulaExpr::Binary {
+                span: Span {
+                    start: lhs.span().start,
+                    end: rhs.span().end,
+                },
+                lhs: Box::new(lhs),
+                rhs: Box::new(rhs),
+                binary_op: parse_binary_op(op),
+            })
+        })
+        .map_prefix(|op, expr| {
+            let expr = expr?;
+            Ok(FormulaExpr::Unary {
+                span: Span {
+                    start: op.as_span().start(),
+                    end: expr.span().end,
+                },
+                expr: Box::new(expr),
+                unary_op: match op.as_rule() {
+                    Rule::op_not => UnaryOp::Not,
+                    Rule::op_neg => UnaryOp::Neg,
+                    rule => unreachable!("unexpected unary operator: {:?}", rule),
+                },
+            })
+        })
+        .parse(pairs)
+}
+
 pub fn parse_formula(input: &str) -> Result<FormulaExpr, FormulaError> {
-    // TODO: must support nested expressions, e.g `(input.port + 2) + 1`
     if input.is_empty() {
         return Err(FormulaError::Empty);
     }
 
     let mut parser =
-        FormulasParser::parse(Rule::expression, input).map_err(FormulaError::ParseError)?;
+        FormulasParser::parse(Rule::formula, input).map_err(FormulaError::ParseError)?;
 
     let pair = parser
         .next()
         .ok_or_else(|| FormulaError::Other("no parse result".to_string()))?; // TODO: error
 
-    Ok(parse_expr(pair))
+    let pratt = pratt_parser();
+    let expr = parse_expr(pair.into_inner(), &pratt)?;
+
+    match parser.next() {
+        Some(pair) if pair.as_rule() == Rule::op_by => {
+            let key_pair = parser
+                .next()
+                .ok_or_else(|| FormulaError::Other("expected a group key".to_string()))?;
+            let key = parse_expr(key_pair.into_inner(), &pratt)?;
+
+            Ok(FormulaExpr::GroupBy {
+                span: Span {
+                    start: expr.span().start,
+                    end: key.span().end,
+                },
+                expr: Box::new(expr),
+                key: Box::new(key),
+            })
+        }
+        _ => Ok(expr),
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{parse_formula, FormulaExpr, FormulaOp, FormulaTerm};
+    use super::{parse_formula, FormulaExpr, FormulaOp, FormulaTerm, Span, UnaryOp};
+
+    fn prop(name: &str) -> FormulaExpr {
+        FormulaExpr::Term(
+            FormulaTerm::Property("input".to_string(), name.to_string()),
+            Span::default(),
+        )
+    }
+
+    fn binary(lhs: FormulaExpr, binary_op: FormulaOp, rhs: FormulaExpr) -> FormulaExpr {
+        FormulaExpr::Binary {
+            lhs: Box::new(lhs),
+            rhs: Box::new(rhs),
+            binary_op,
+            span: Span::default(),
+        }
+    }
 
     #[test]
     fn test_basic_parser() {
@@ -198,36 +514,294 @@ mod tests {
         let parsed = parse_formula("input.port >= 8080").unwrap();
 
         assert_eq!(
-            parsed,
+            parsed.strip_spans(),
             FormulaExpr::Binary {
-                lhs: Box::new(FormulaExpr::Term(FormulaTerm::Property(
-                    "input".to_string(),
-                    "port".to_string()
-                ))),
-                rhs: Box::new(FormulaExpr::Term(FormulaTerm::Number(8080))),
-                binary_op: FormulaOp::GtEq
+                lhs: Box::new(FormulaExpr::Term(
+                    FormulaTerm::Property("input".to_string(), "port".to_string()),
+                    Span::default()
+                )),
+                rhs: Box::new(FormulaExpr::Term(
+                    FormulaTerm::Number(8080),
+                    Span::default()
+                )),
+                binary_op: FormulaOp::GtEq,
+                span: Span::default(),
             }
         );
 
         let parsed = parse_formula("input.process_name = \"bash\"").unwrap();
 
         assert_eq!(
-            parsed,
+            parsed.strip_spans(),
             FormulaExpr::Binary {
-                lhs: Box::new(FormulaExpr::Term(FormulaTerm::Property(
-                    "input".to_string(),
-                    "process_name".to_string()
-                ))),
-                rhs: Box::new(FormulaExpr::Term(FormulaTerm::String("bash".to_string()))),
-                binary_op: FormulaOp::Eq
+                lhs: Box::new(FormulaExpr::Term(
+                    FormulaTerm::Property("input".to_string(), "process_name".to_string()),
+                    Span::default()
+                )),
+                rhs: Box::new(FormulaExpr::Term(
+                    FormulaTerm::String("bash".to_string()),
+                    Span::default()
+                )),
+                binary_op: FormulaOp::Eq,
+                span: Span::default(),
             }
         );
 
         let parsed = parse_formula("count(input)").unwrap();
 
         assert_eq!(
-            parsed,
-            FormulaExpr::Term(FormulaTerm::CountCall("input".to_string(),))
+            parsed.strip_spans(),
+            FormulaExpr::Term(FormulaTerm::CountCall("input".to_string()), Span::default())
+        );
+    }
+
+    #[test]
+    fn test_number_literals() {
+        assert_eq!(
+            parse_formula("18446744073709551615").unwrap().strip_spans(),
+            u64::MAX.into()
         );
+        assert_eq!(
+            parse_formula("-9223372036854775808").unwrap().strip_spans(),
+            FormulaExpr::Term(FormulaTerm::SignedNumber(i64::MIN), Span::default())
+        );
+
+        let source = "input.pid > 99999999999999999999";
+        let diagnostics = parse_formula(source)
+            .unwrap_err()
+            .into_diagnostics(1, "value", source);
+        assert_eq!(
+            (diagnostics[0].span.start, diagnostics[0].span.end),
+            (12, 32)
+        );
+        assert!(parse_formula("-9223372036854775809").is_err());
+        assert!(parse_formula("[1, 18446744073709551616]").is_err());
+    }
+
+    #[test]
+    fn test_boolean_literals() {
+        assert_eq!(
+            parse_formula("input.a && false").unwrap().strip_spans(),
+            binary(
+                prop("a"),
+                FormulaOp::And,
+                FormulaExpr::Term(FormulaTerm::Boolean(false), Span::default())
+            )
+        );
+        assert_eq!(
+            parse_formula("!true").unwrap().strip_spans(),
+            FormulaExpr::Unary {
+                expr: Box::new(FormulaExpr::Term(
+                    FormulaTerm::Boolean(true),
+                    Span::default()
+                )),
+                unary_op: UnaryOp::Not,
+                span: Span::default(),
+            }
+        );
+    }
+
+    #[test]
+    fn test_chained_expressions() {
+        let parsed = parse_formula("input.a = 1 && input.b = 2 && input.c = 3").unwrap();
+
+        assert_eq!(
+            parsed.strip_spans(),
+            binary(
+                binary(
+                    binary(prop("a"), FormulaOp::Eq, 1.into()),
+                    FormulaOp::And,
+                    binary(prop("b"), FormulaOp::Eq, 2.into()),
+                ),
+                FormulaOp::And,
+                binary(prop("c"), FormulaOp::Eq, 3.into()),
+            )
+        );
+
+        let parsed = parse_formula("input.a + 1 + 2").unwrap();
+
+        assert_eq!(
+            parsed.strip_spans(),
+            binary(
+                binary(prop("a"), FormulaOp::Add, 1.into()),
+                FormulaOp::Add,
+                2.into(),
+            )
+        );
+    }
+
+    #[test]
+    fn test_operator_precedence() {
+        let parsed = parse_formula("input.a = 1 || input.b = 2 && input.c = 3").unwrap();
+
+        assert_eq!(
+            parsed.strip_spans(),
+            binary(
+                binary(prop("a"), FormulaOp::Eq, 1.into()),
+                FormulaOp::Or,
+                binary(
+                    binary(prop("b"), FormulaOp::Eq, 2.into()),
+                    FormulaOp::And,
+                    binary(prop("c"), FormulaOp::Eq, 3.into()),
+                ),
+            )
+        );
+
+        let parsed =
+            parse_formula("(input.a = 1 || input.b = 2) && input.port + 1 >= 8080").unwrap();
+
+        assert_eq!(
+            parsed.strip_spans(),
+            binary(
+                binary(
+                    binary(prop("a"), FormulaOp::Eq, 1.into()),
+                    FormulaOp::Or,
+                    binary(prop("b"), FormulaOp::Eq, 2.into()),
+                ),
+                FormulaOp::And,
+                binary(
+                    binary(prop("port"), FormulaOp::Add, 1.into()),
+                    FormulaOp::GtEq,
+                    8080.into(),
+                ),
+            )
+        );
+    }
+
+    #[test]
+    fn test_trailing_input() {
+        assert!(parse_formula("input.a = 1 input.b").is_err());
+    }
+
+    #[test]
+    fn test_arithmetic_expressions() {
+        let parsed = parse_formula("input.arg1 / 1024 - 1 * 2 % 3").unwrap();
+
+        assert_eq!(
+            parsed.strip_spans(),
+            binary(
+                binary(prop("arg1"), FormulaOp::Div, 1024.into()),
+                FormulaOp::Sub,
+                binary(
+                    binary(1.into(), FormulaOp::Mul, 2.into()),
+                    FormulaOp::Mod,
+                    3.into(),
+                ),
+            )
+        );
+
+        let parsed = parse_formula("input.flags & 1 << 4 | 2 = 0").unwrap();
+
+        assert_eq!(
+            parsed.strip_spans(),
+            binary(
+                binary(
+                    binary(
+                        prop("flags"),
+                        FormulaOp::BitAnd,
+                        binary(1.into(), FormulaOp::Shl, 4.into()),
+                    ),
+                    FormulaOp::BitOr,
+                    2.into(),
+                ),
+                FormulaOp::Eq,
+                0.into(),
+            )
+        );
+    }
+
+    #[test]
+    fn test_unary_expressions() {
+        let parsed = parse_formula("!(input.pid = 1) && -input.a < -5").unwrap();
+
+        assert_eq!(
+            parsed.strip_spans(),
+            binary(
+                FormulaExpr::Unary {
+                    expr: Box::new(binary(prop("pid"), FormulaOp::Eq, 1.into())),
+                    unary_op: UnaryOp::Not,
+                    span: Span::default(),
+                },
+                FormulaOp::And,
+                binary(
+                    FormulaExpr::Unary {
+                        expr: Box::new(prop("a")),
+                        unary_op: UnaryOp::Neg,
+                        span: Span::default(),
+                    },
+                    FormulaOp::Lt,
+                    FormulaExpr::Term(FormulaTerm::SignedNumber(-5), Span::default()),
+                ),
+            )
+        );
+
+        let parsed = parse_formula("input.a -1").unwrap();
+        assert_eq!(
+            parsed.strip_spans(),
+            binary(prop("a"), FormulaOp::Sub, 1.into())
+        );
+    }
+
+    #[test]
+    fn test_membership_expressions() {
+        let parsed = parse_formula("input.pid in [1, 2, 3] && input.index not in []").unwrap();
+
+        assert_eq!(
+            parsed.strip_spans(),
+            binary(
+                binary(
+                    prop("pid"),
+                    FormulaOp::In,
+                    FormulaExpr::List(vec![1.into(), 2.into(), 3.into()], Span::default()),
+                ),
+                FormulaOp::And,
+                binary(
+                    prop("index"),
+                    FormulaOp::NotIn,
+                    FormulaExpr::List(vec![], Span::default())
+                ),
+            )
+        );
+
+        let parsed = parse_formula("\"sh\" in input.process_name").unwrap();
+
+        assert_eq!(
+            parsed.strip_spans(),
+            binary("sh".into(), FormulaOp::In, prop("process_name"))
+        );
+    }
+
+    #[test]
+    fn test_expression_spans() {
+        let parsed = parse_formula("input.pid = 1 && !(input.a < 2)").unwrap();
+
+        let (lhs, rhs, span) = match parsed {
+            FormulaExpr::Binary { lhs, rhs, span, .. } => (lhs, rhs, span),
+            _ => panic!("expected a binary expression"),
+        };
+        assert_eq!((span.start, span.end), (0, 31));
+        assert_eq!((lhs.span().start, lhs.span().end), (0, 13));
+        assert_eq!((rhs.span().start, rhs.span().end), (17, 31));
+    }
+
+    #[test]
+    fn test_group_by() {
+        let parsed = parse_formula("count(input) by input.pid % 4").unwrap();
+
+        assert_eq!(
+            parsed.strip_spans(),
+            FormulaExpr::GroupBy {
+                expr: Box::new(FormulaExpr::Term(
+                    FormulaTerm::CountCall("input".to_string()),
+                    Span::default()
+                )),
+                key: Box::new(binary(prop("pid"), FormulaOp::Mod, 4.into())),
+                span: Span::default(),
+            }
+        );
+        assert_eq!((parsed.span().start, parsed.span().end), (0, 29));
+
+        assert!(parse_formula("count(input) by").is_err());
+        assert!(parse_formula("input.by = 1").is_ok());
     }
 }
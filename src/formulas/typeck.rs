@@ -0,0 +1,420 @@
+//! Type checker for the formula language.
+//!
+//! Infers types of formula expressions before any code is generated,
+//! so that invalid programs can be rejected early.
+
+use super::{
+    eval, read_size, AggregateFunc, Builtin, Diagnostic, ExprType, FormulaExpr, FormulaOp,
+    FormulaTerm, Span, UnaryOp,
+};
+use crate::{dsl::NodeId, nodes::OutputType};
+
+/// Infers the type of a formula in the node `property`.
+/// `input` is the output type of the previous node, used to resolve `input.*` properties.
+/// Returns all type errors found in the formula.
+pub fn check_types(
+    node_id: NodeId,
+    property: &str,
+    expr: &FormulaExpr,
+    input: Option<&OutputType>,
+) -> Result<ExprType, Vec<Diagnostic>> {
+    let mut checker = TypeChecker {
+        node_id,
+        property,
+        input,
+        errors: Vec::new(),
+    };
+
+    match checker.infer(expr) {
+        Some(ty) if checker.errors.is_empty() => Ok(ty),
+        _ => Err(checker.errors),
+    }
+}
+
+struct TypeChecker<'a> {
+    node_id: NodeId,
+    property: &'a str,
+    input: Option<&'a OutputType>,
+    errors: Vec<Diagnostic>,
+}
+
+impl TypeChecker<'_> {
+    fn error(&mut self, span: Span, message: String) -> Option<ExprType> {
+        self.errors
+            .push(Diagnostic::new(self.node_id, self.property, span, message));
+        None
+    }
+
+    fn error_with_hint(&mut self, span: Span, message: String, hint: &str) -> Option<ExprType> {
+        self.errors
+            .push(Diagnostic::new(self.node_id, self.property, span, message).with_hint(hint));
+        None
+    }
+
+    /// Returns `None` if the expression type can't be inferred because of errors.
+    fn infer(&mut self, expr: &FormulaExpr) -> Option<ExprType> {
+        match expr {
+            FormulaExpr::Term(term, span) => self.infer_term(term, *span),
+            FormulaExpr::Binary {
+                lhs,
+                rhs,
+                binary_op,
+                span,
+            } if binary_op.is_membership() => self.infer_membership(lhs, rhs, *binary_op, *span),
+            FormulaExpr::Binary {
+                lhs,
+                rhs,
+                binary_op,
+                span,
+            } => {
+                // infer both operands to report all errors at once
+                let lhs_ty = self.infer(lhs);
+                let rhs_ty = self.infer(rhs);
+
+                let (lhs_ty, rhs_ty) = (lhs_ty?, rhs_ty?);
+
+                // strings are concatenated only by constant folding, BPF code can't do it
+                if *binary_op == FormulaOp::Add
+                    && lhs_ty == ExprType::String
+                    && rhs_ty == ExprType::String
+                    && (eval(lhs).is_err() || eval(rhs).is_err())
+                {
+                    return self.error_with_hint(
+                        *span,
+                        "only constant strings can be concatenated".to_string(),
+                        "concatenate string literals, e.g. `\"/usr/lib/\" + \"libc.so.6\"`",
+                    );
+                }
+
+                self.infer_binary(*binary_op, lhs_ty, rhs_ty, *span)
+            }
+            FormulaExpr::Unary {
+                expr,
+                unary_op,
+                span,
+            } => match (unary_op, self.infer(expr)?) {
+                (UnaryOp::Not, ty) if ty == ExprType::Boolean || ty.is_numeric() => {
+                    Some(ExprType::Boolean)
+                }
+                (UnaryOp::Neg, ty) if ty.is_numeric() => Some(ExprType::SignedNumber),
+                (unary_op, ty) => {
+                    self.error(*span, format!("cannot apply `{}` to a {}", unary_op, ty))
+                }
+            },
+            FormulaExpr::Call { func, args, span } => self.infer_call(func, args, *span),
+            FormulaExpr::List(_, span) => self.error_with_hint(
+                *span,
+                "lists can be used only with `in` and `not in`".to_string(),
+                "check if a value is in the list, e.g. `input.pid in [1, 2]`",
+            ),
+            FormulaExpr::GroupBy { expr, key, .. } => self.infer_group_by(expr, key),
+        }
+    }
+
+    fn infer_term(&mut self, term: &FormulaTerm, span: Span) -> Option<ExprType> {
+        match term {
+            FormulaTerm::Property(object, prop_name) => {
+                if object != "input" {
+                    return self.error(span, format!("unknown object `{}`", object));
+                }
+
+                match self.input {
+                    Some(OutputType::Struct(output_struct)) => {
+                        match (
+                            output_struct.property_type(prop_name),
+                            output_struct.unavailable_reason(prop_name),
+                        ) {
+                            (Some(ty), _) => Some(ty),
+                            (None, Some(reason)) => self.error(
+                                span,
+                                format!("`{}.{}` is not available: {}", object, prop_name, reason),
+                            ),
+                            (None, None) => self.error(
+                                span,
+                                format!("unknown property `{}.{}`", object, prop_name),
+                            ),
+                        }
+                    }
+                    _ => self.error_with_hint(
+                        span,
+                        "`input` is not available here".to_string(),
+                        "connect this node to a probe",
+                    ),
+                }
+            }
+            FormulaTerm::Number(_) => Some(ExprType::Number),
+            FormulaTerm::SignedNumber(_) => Some(ExprType::SignedNumber),
+            FormulaTerm::Boolean(_) => Some(ExprType::Boolean),
+            FormulaTerm::String(_) => Some(ExprType::String),
+            FormulaTerm::CountCall(_) => Some(ExprType::Counter),
+            FormulaTerm::Float(_) => self.error_with_hint(
+                span,
+                "fractional numbers can be used only as a percentile rank".to_string(),
+                "use an integer number",
+            ),
+        }
+    }
+
+    fn infer_call(&mut self, func: &str, args: &[FormulaExpr], span: Span) -> Option<ExprType> {
+        if AggregateFunc::is_aggregate(func) {
+            return self.infer_aggregate(func, args, span);
+        }
+
+        let (arg_types, ret_ty) = match Builtin::lookup(func) {
+            Some(builtin) => (builtin.args, builtin.ret),
+            None => return self.error(span, format!("unknown function `{}`", func)),
+        };
+
+        if args.len() != arg_types.len() {
+            return self.error(
+                span,
+                format!(
+                    "`{}` takes {} argument(s), found {}",
+                    func,
+                    arg_types.len(),
+                    args.len()
+                ),
+            );
+        }
+
+        let mut valid = true;
+
+        for (arg, expected_ty) in args.iter().zip(arg_types) {
+            match self.infer(arg) {
+                Some(ty) if ty == *expected_ty => {}
+                Some(ty) => {
+                    self.error(
+                        arg.span(),
+                        format!("expected a {} argument, found a {}", expected_ty, ty),
+                    );
+                    valid = false;
+                }
+                None => valid = false,
+            }
+        }
+
+        if valid && (func == "str" || func == "buf") {
+            if let Err(message) = read_size(func, &args[1]) {
+                return self.error(args[1].span(), message);
+            }
+        }
+
+        valid.then_some(ret_ty)
+    }
+
+    fn infer_aggregate(
+        &mut self,
+        func: &str,
+        args: &[FormulaExpr],
+        span: Span,
+    ) -> Option<ExprType> {
+        if let Err(message) = AggregateFunc::from_call(func, args) {
+            return self.error(span, message);
+        }
+        let arg = &args[0];
+
+        match self.infer(arg)? {
+            ty if ty.is_numeric() => Some(ExprType::Counter),
+            ty => self.error(
+                arg.span(),
+                format!("`{}` expects a number, found a {}", func, ty),
+            ),
+        }
+    }
+
+    fn infer_group_by(&mut self, expr: &FormulaExpr, key: &FormulaExpr) -> Option<ExprType> {
+        // infer both expressions to report all errors at once
+        let ty = self.infer(expr);
+        let key_ty = self.infer(key);
+
+        match expr.aggregate() {
+            // the aggregate is invalid and the error has been already reported
+            _ if ty.is_none() => return None,
+            Some(aggregate) if aggregate.func.bucket_count().is_some() => {
+                return self.error(
+                    expr.span(),
+                    "histograms and percentiles can't be grouped".to_string(),
+                );
+            }
+            Some(_) => {}
+            None => {
+                return self.error_with_hint(
+                    expr.span(),
+                    "`by` can be used only with aggregate functions".to_string(),
+                    "group an aggregate by a key, e.g. `count(input) by input.pid`",
+                );
+            }
+        }
+
+        match key_ty? {
+            ExprType::Counter => self.error(key.span(), "cannot group by an aggregate".to_string()),
+            _ => ty,
+        }
+    }
+
+    fn infer_binary(
+        &mut self,
+        binary_op: FormulaOp,
+        lhs_ty: ExprType,
+        rhs_ty: ExprType,
+        span: Span,
+    ) -> Option<ExprType> {
+        let is_condition = |ty: ExprType| ty == ExprType::Boolean || ty.is_numeric();
+        let both_numeric = lhs_ty.is_numeric() && rhs_ty.is_numeric();
+
+        match binary_op {
+            FormulaOp::And | FormulaOp::Or if is_condition(lhs_ty) && is_condition(rhs_ty) => {
+                Some(ExprType::Boolean)
+            }
+            FormulaOp::Eq | FormulaOp::NotEq
+                if both_numeric
+                    || (lhs_ty == rhs_ty
+                        && (lhs_ty == ExprType::String || lhs_ty == ExprType::Boolean)) =>
+            {
+                Some(ExprType::Boolean)
+            }
+            FormulaOp::Lt | FormulaOp::Gt | FormulaOp::LtEq | FormulaOp::GtEq if both_numeric => {
+                Some(ExprType::Boolean)
+            }
+            FormulaOp::Add if lhs_ty == ExprType::String && rhs_ty == ExprType::String => {
+                Some(ExprType::String)
+            }
+            FormulaOp::Add
+            | FormulaOp::Sub
+            | FormulaOp::Mul
+            | FormulaOp::Div
+            | FormulaOp::Mod
+            | FormulaOp::BitAnd
+            | FormulaOp::BitOr
+            | FormulaOp::Shl
+            | FormulaOp::Shr
+                if both_numeric =>
+            {
+                if lhs_ty == ExprType::SignedNumber || rhs_ty == ExprType::SignedNumber {
+                    Some(ExprType::SignedNumber)
+                } else {
+                    Some(ExprType::Number)
+                }
+            }
+            binary_op => self.error(
+                span,
+                format!(
+                    "cannot apply `{}` to a {} and a {}",
+                    binary_op, lhs_ty, rhs_ty
+                ),
+            ),
+        }
+    }
+
+    fn infer_membership(
+        &mut self,
+        lhs: &FormulaExpr,
+        rhs: &FormulaExpr,
+        binary_op: FormulaOp,
+        span: Span,
+    ) -> Option<ExprType> {
+        let lhs_ty = self.infer(lhs);
+
+        match rhs {
+            FormulaExpr::List(elems, _) => {
+                let mut valid = lhs_ty.is_some();
+
+                for elem in elems {
+                    match (lhs_ty, self.infer(elem)) {
+                        (Some(lhs_ty), Some(elem_ty))
+                            if (lhs_ty.is_numeric() && elem_ty.is_numeric())
+                                || (lhs_ty == ExprType::String && elem_ty == ExprType::String) => {}
+                        (Some(lhs_ty), Some(elem_ty)) => {
+                            self.error(
+                                elem.span(),
+                                format!("cannot look for a {} in a list of {}", lhs_ty, elem_ty),
+                            );
+                            valid = false;
+                        }
+                        _ => valid = false,
+                    }
+                }
+
+                valid.then_some(ExprType::Boolean)
+            }
+            rhs => {
+                // searching for a substring
+                let rhs_ty = self.infer(rhs);
+
+                match (lhs_ty?, rhs_ty?) {
+                    (ExprType::String, ExprType::String) => {
+                        if let Some(FormulaTerm::String(_)) = lhs.term() {
+                            Some(ExprType::Boolean)
+                        } else {
+                            self.error_with_hint(
+                                lhs.span(),
+                                "substring must be a string literal".to_string(),
+                                "put the substring on the left, e.g. `\"sh\" in input.process_name`",
+                            )
+                        }
+                    }
+                    (lhs_ty, rhs_ty) => self.error(
+                        span,
+                        format!(
+                            "cannot apply `{}` to a {} and a {}",
+                            binary_op, lhs_ty, rhs_ty
+                        ),
+                    ),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::formulas::parse_formula;
+
+    #[test]
+    fn test_infer_types() {
+        let check = |formula| check_types(0, "value", &parse_formula(formula).unwrap(), None);
+
+        assert_eq!(check("1 + -2"), Ok(ExprType::SignedNumber));
+        assert_eq!(check("\"a\" = \"b\" || 1 < 2"), Ok(ExprType::Boolean));
+        assert_eq!(check("\"ab\" in \"abc\""), Ok(ExprType::Boolean));
+        assert_eq!(check("sum(1) by 2 > 1"), Ok(ExprType::Counter));
+        assert_eq!(check("nstime() - len(\"abc\")"), Ok(ExprType::Number));
+        assert!(check("len(1)").is_err());
+        assert!(check("cpu(1)").is_err());
+        assert_eq!(check("str(1, 64) = \"bash\""), Ok(ExprType::Boolean));
+        assert_eq!(check("buf(1, nstime())"), Ok(ExprType::String));
+        assert!(check("str(1, nstime())").is_err());
+        assert!(check("str(1, 5000)").is_err());
+        assert!(check("1 by 2").is_err());
+        assert!(check("hist(1) by 2").is_err());
+    }
+
+    #[test]
+    fn test_string_concatenation() {
+        let check = |formula| check_types(0, "value", &parse_formula(formula).unwrap(), None);
+
+        assert_eq!(check("\"a\" + \"b\" = \"ab\""), Ok(ExprType::Boolean));
+        assert_eq!(check("\"a\" + (\"b\" + \"c\")"), Ok(ExprType::String));
+        assert!(check("\"a\" + str(1, 8)").is_err());
+
+        let errors = check("buf(1, nstime()) + \"b\"").unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!((errors[0].span.start, errors[0].span.end), (0, 22));
+    }
+
+    #[test]
+    fn test_report_all_errors() {
+        let errors = check_types(
+            0,
+            "value",
+            &parse_formula("(1 + \"a\") < (!\"b\")").unwrap(),
+            None,
+        )
+        .unwrap_err();
+
+        assert_eq!(errors.len(), 2);
+        assert_eq!((errors[0].span.start, errors[0].span.end), (0, 9));
+        assert_eq!((errors[1].span.start, errors[1].span.end), (12, 18));
+    }
+}
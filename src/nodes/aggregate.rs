@@ -0,0 +1,839 @@
+//! Generates BPF code for aggregate functions and reads aggregate values at runtime.
+
+use std::time::Duration;
+
+use futures::channel::mpsc::TrySendError;
+use inkwell::{
+    values::{AnyValueEnum, IntValue, PointerValue},
+    AddressSpace, AtomicOrdering, AtomicRMWBinOp, IntPredicate,
+};
+use redbpf::Map;
+use serde::Serialize;
+use tokio::time;
+use tracing::{info, warn};
+
+use super::{send_value, CodegenCtx, ExprValue, OutputType};
+use crate::{
+    codegen::{
+        bpf_map_lookup_elem, bpf_map_update_elem, generate_bpf_map, generate_map_lookup,
+        generate_null_check, CodegenError, BPF_MAP_TYPE_ARRAY, BPF_MAP_TYPE_HASH,
+        BPF_MAP_TYPE_PERCPU_ARRAY, BPF_MAP_TYPE_PERCPU_HASH, BPF_NOEXIST,
+    },
+    dsl::NodeId,
+    formulas::{
+        self, Aggregate, AggregateFunc, AggregateState, ExprType, GroupRow, HistogramBucket,
+        LinearBuckets, Value,
+    },
+    runtime::{LoadedState, RuntimeError},
+    ws::{Message, MsgChannelTx},
+};
+
+/// Returns the name of a BPF map storing the aggregate state of a node.
+fn map_name(node_id: NodeId) -> String {
+    format!("aggregate_{}", node_id)
+}
+
+/// min and max are updated with a plain load and store, so they're stored in a per-CPU map
+/// to keep programs on other CPUs from racing with the update. Programs on the same CPU can
+/// still interleave, e.g. when a probe fires in an interrupt or a preemptible kernel switches
+/// tasks mid-update; the race is accepted, as it can only lose a value or a count.
+fn is_per_cpu(func: AggregateFunc) -> bool {
+    matches!(func, AggregateFunc::Min | AggregateFunc::Max)
+}
+
+/// Generates code that updates the aggregate state with a new value.
+/// Returns the type of the aggregated value and the type of the group key, if there's one.
+pub fn codegen_aggregate<'a>(
+    ctx: &mut CodegenCtx<'a>,
+    node_id: NodeId,
+    aggregate: Aggregate,
+    prev_node_output: Option<&OutputType>,
+) -> Result<(ExprType, Option<ExprType>), CodegenError> {
+    let i64_ty = ctx.llvm_context.i64_type();
+
+    let (value, value_ty) = match aggregate.arg {
+        Some(arg) => {
+            let arg_val = formulas::generate_expr_code(ctx, arg, prev_node_output)?;
+            if !arg_val.ty.is_numeric() {
+                return Err(CodegenError::Other(format!(
+                    "cannot aggregate a {}",
+                    arg_val.ty
+                )));
+            }
+
+            let value = formulas::widen_int(
+                ctx,
+                arg_val.value.into_int_value(),
+                arg_val.ty == ExprType::SignedNumber,
+            );
+            (value, arg_val.ty)
+        }
+        None => (i64_ty.const_int(1, false), ExprType::Number),
+    };
+
+    if let Some(bucket_count) = aggregate.func.bucket_count() {
+        codegen_histogram(ctx, node_id, aggregate.func, value, value_ty, bucket_count);
+        return Ok((value_ty, None));
+    }
+
+    let (state_ptr, key_ty) = match aggregate.key {
+        Some(key) => {
+            let key_val = formulas::generate_expr_code(ctx, key, prev_node_output)?;
+            let key_ty = key_val.ty;
+            let state_ptr = generate_group_lookup(ctx, node_id, aggregate.func, key_val)?;
+            (state_ptr, Some(key_ty))
+        }
+        None => {
+            let map_type = if is_per_cpu(aggregate.func) {
+                BPF_MAP_TYPE_PERCPU_ARRAY
+            } else {
+                BPF_MAP_TYPE_ARRAY
+            };
+            let map = generate_bpf_map(ctx, &map_name(node_id), map_type, 4, 16, 1);
+
+            (generate_state_lookup(ctx, map.as_pointer_value()), None)
+        }
+    };
+    let acc_ptr = generate_state_field(ctx, state_ptr, 0);
+    let count_ptr = generate_state_field(ctx, state_ptr, 1);
+
+    let one = i64_ty.const_int(1, false);
+
+    match aggregate.func {
+        AggregateFunc::Count => {
+            generate_atomic_add(ctx, count_ptr, one);
+        }
+        AggregateFunc::Sum | AggregateFunc::Avg => {
+            generate_atomic_add(ctx, acc_ptr, value);
+            generate_atomic_add(ctx, count_ptr, one);
+        }
+        AggregateFunc::Min | AggregateFunc::Max => {
+            // not atomic, see `is_per_cpu`
+            let acc = ctx.builder.build_load(acc_ptr, "acc").into_int_value();
+            let count = ctx.builder.build_load(count_ptr, "count").into_int_value();
+
+            let signed = value_ty == ExprType::SignedNumber;
+            let predicate = match (aggregate.func, signed) {
+                (AggregateFunc::Min, true) => IntPredicate::SLT,
+                (AggregateFunc::Min, false) => IntPredicate::ULT,
+                (_, true) => IntPredicate::SGT,
+                (_, false) => IntPredicate::UGT,
+            };
+
+            // the accumulator is not initialised until the first value is stored
+            let is_first = ctx.builder.build_int_compare(
+                IntPredicate::EQ,
+                count,
+                i64_ty.const_zero(),
+                "is_first",
+            );
+            let is_better = ctx
+                .builder
+                .build_int_compare(predicate, value, acc, "is_better");
+            let replace = ctx.builder.build_or(is_first, is_better, "replace");
+
+            let new_acc = ctx.builder.build_select(replace, value, acc, "new_acc");
+            ctx.builder.build_store(acc_ptr, new_acc);
+
+            let new_count = ctx.builder.build_int_add(count, one, "new_count");
+            ctx.builder.build_store(count_ptr, new_count);
+        }
+    }
+
+    Ok((value_ty, key_ty))
+}
+
+/// Looks up the aggregate state of a group in a BPF hash map; new groups start with an empty state.
+/// Exits the program if the map is full.
+fn generate_group_lookup<'a>(
+    ctx: &mut CodegenCtx<'a>,
+    node_id: NodeId,
+    func: AggregateFunc,
+    key: ExprValue<'a>,
+) -> Result<PointerValue<'a>, CodegenError> {
+    let (key_ptr, key_size) = generate_group_key(ctx, key)?;
+
+    let map_type = if is_per_cpu(func) {
+        BPF_MAP_TYPE_PERCPU_HASH
+    } else {
+        BPF_MAP_TYPE_HASH
+    };
+    let map = generate_bpf_map(
+        ctx,
+        &map_name(node_id),
+        map_type,
+        key_size,
+        16,
+        formulas::MAX_GROUPS,
+    )
+    .as_pointer_value();
+
+    let state_ty = ctx.llvm_context.i64_type().array_type(2);
+    let state_ptr_ty = state_ty.ptr_type(AddressSpace::Generic);
+
+    ctx.builder.position_at_end(ctx.allocs_block);
+    let empty_state = ctx.builder.build_alloca(state_ty, "empty_state");
+    ctx.builder.position_at_end(ctx.current_block);
+
+    let found_ptr = bpf_map_lookup_elem(ctx, map, key_ptr, state_ptr_ty);
+    let found = ctx.builder.build_is_not_null(found_ptr, "group_found");
+    let lookup_block = ctx.current_block;
+
+    let insert_block = ctx
+        .llvm_context
+        .append_basic_block(ctx.func, "group_insert");
+    let merge_block = ctx.llvm_context.append_basic_block(ctx.func, "group_merge");
+    ctx.builder
+        .build_conditional_branch(found, merge_block, insert_block);
+
+    // another CPU can insert the same key concurrently, so the insert result is ignored
+    // and the state is looked up again
+    ctx.set_current_block(insert_block);
+    ctx.builder.build_store(empty_state, state_ty.const_zero());
+    bpf_map_update_elem(ctx, map, key_ptr, empty_state, BPF_NOEXIST);
+    let inserted_ptr = bpf_map_lookup_elem(ctx, map, key_ptr, state_ptr_ty);
+    generate_null_check(ctx, inserted_ptr);
+    let inserted_block = ctx.current_block;
+    ctx.builder.build_unconditional_branch(merge_block);
+
+    ctx.set_current_block(merge_block);
+    let state_ptr = ctx.builder.build_phi(state_ptr_ty, "group_state");
+    state_ptr.add_incoming(&[(&found_ptr, lookup_block), (&inserted_ptr, inserted_block)]);
+
+    Ok(state_ptr.as_basic_value().into_pointer_value())
+}
+
+/// Stores a group key on the stack and returns a pointer to it along with the key size.
+/// Numbers and booleans are stored as 64-bit integers; strings are copied into a zero-padded
+/// buffer of `MAX_GROUP_KEY_LEN` bytes.
+fn generate_group_key<'a>(
+    ctx: &mut CodegenCtx<'a>,
+    key: ExprValue<'a>,
+) -> Result<(PointerValue<'a>, u32), CodegenError> {
+    match (key.ty, key.value) {
+        (ExprType::Number | ExprType::SignedNumber | ExprType::Boolean, value) => {
+            let i64_ty = ctx.llvm_context.i64_type();
+
+            ctx.builder.position_at_end(ctx.allocs_block);
+            let key_ptr = ctx.builder.build_alloca(i64_ty, "group_key");
+            ctx.builder.position_at_end(ctx.current_block);
+
+            let value = formulas::widen_int(
+                ctx,
+                value.into_int_value(),
+                key.ty == ExprType::SignedNumber,
+            );
+            ctx.builder.build_store(key_ptr, value);
+
+            Ok((key_ptr, 8))
+        }
+        (ExprType::String, value) => {
+            let key_ty = ctx
+                .llvm_context
+                .i8_type()
+                .array_type(formulas::MAX_GROUP_KEY_LEN);
+
+            ctx.builder.position_at_end(ctx.allocs_block);
+            let key_ptr = ctx.builder.build_alloca(key_ty, "group_key");
+            ctx.builder.position_at_end(ctx.current_block);
+
+            // the whole key is hashed, so bytes after the string must be zeroed
+            ctx.builder.build_store(key_ptr, key_ty.const_zero());
+
+            match value {
+                AnyValueEnum::PointerValue(buf) => {
+                    let buf_len = buf.get_type().get_element_type().into_array_type().len();
+                    let len = buf_len.min(formulas::MAX_GROUP_KEY_LEN);
+
+                    generate_key_string_copy(ctx, key_ptr, buf, len);
+                }
+                AnyValueEnum::ArrayValue(literal) => {
+                    if literal.get_type().len() > formulas::MAX_GROUP_KEY_LEN {
+                        return Err(CodegenError::Other(format!(
+                            "group key can't be longer than {} bytes",
+                            formulas::MAX_GROUP_KEY_LEN
+                        )));
+                    }
+
+                    let literal_ptr = ctx.builder.build_pointer_cast(
+                        key_ptr,
+                        literal.get_type().ptr_type(AddressSpace::Generic),
+                        "group_key_literal",
+                    );
+                    ctx.builder.build_store(literal_ptr, literal);
+                }
+                value => {
+                    return Err(CodegenError::Other(format!(
+                        "unexpected string value: {:?}",
+                        value
+                    )))
+                }
+            }
+
+            Ok((key_ptr, formulas::MAX_GROUP_KEY_LEN))
+        }
+        (ty, _) => Err(CodegenError::Other(format!("cannot group by a {}", ty))),
+    }
+}
+
+/// Copies a NUL-terminated string of at most `len` bytes into the group key.
+/// Read buffers aren't necessarily zeroed after the terminator, so every byte after it
+/// is replaced with zero to keep equal strings hashing to the same key.
+fn generate_key_string_copy<'a>(
+    ctx: &mut CodegenCtx<'a>,
+    key_ptr: PointerValue<'a>,
+    buf: PointerValue<'a>,
+    len: u32,
+) {
+    let i8_ty = ctx.llvm_context.i8_type();
+    let i32_ty = ctx.llvm_context.i32_type();
+    let nul = i8_ty.const_zero();
+
+    let mut terminated = ctx.llvm_context.bool_type().const_zero();
+
+    for index in 0..len {
+        let indices = [i32_ty.const_zero(), i32_ty.const_int(index as u64, false)];
+        let (src_ptr, dst_ptr) = unsafe {
+            (
+                ctx.builder.build_in_bounds_gep(buf, &indices, "key_src"),
+                ctx.builder
+                    .build_in_bounds_gep(key_ptr, &indices, "key_dst"),
+            )
+        };
+
+        let char = ctx.builder.build_load(src_ptr, "key_char").into_int_value();
+        let is_nul = ctx
+            .builder
+            .build_int_compare(IntPredicate::EQ, char, nul, "is_nul");
+        terminated = ctx.builder.build_or(terminated, is_nul, "terminated");
+
+        let char = ctx.builder.build_select(terminated, nul, char, "key_char");
+        ctx.builder.build_store(dst_ptr, char);
+    }
+}
+
+/// Generates code that increments a histogram bucket the value falls into.
+/// Buckets are stored in a BPF array.
+fn codegen_histogram<'a>(
+    ctx: &mut CodegenCtx<'a>,
+    node_id: NodeId,
+    func: AggregateFunc,
+    value: IntValue<'a>,
+    value_ty: ExprType,
+    bucket_count: u32,
+) {
+    let signed = value_ty == ExprType::SignedNumber;
+
+    let bucket = match func {
+        AggregateFunc::LHist(buckets) => generate_linear_bucket(ctx, value, signed, buckets),
+        AggregateFunc::Percentile(_) => generate_log_linear_bucket(ctx, value, signed),
+        _ => generate_log2_bucket(ctx, value, signed),
+    };
+    let bucket = ctx
+        .builder
+        .build_int_truncate(bucket, ctx.llvm_context.i32_type(), "bucket");
+
+    let map = generate_bpf_map(
+        ctx,
+        &map_name(node_id),
+        BPF_MAP_TYPE_ARRAY,
+        4,
+        8,
+        bucket_count,
+    );
+
+    let i64_ty = ctx.llvm_context.i64_type();
+    let count_ptr = generate_map_lookup(
+        ctx,
+        map.as_pointer_value(),
+        bucket,
+        i64_ty.ptr_type(AddressSpace::Generic),
+    );
+    generate_atomic_add(ctx, count_ptr, i64_ty.const_int(1, false));
+}
+
+/// Returns the index of a log2 histogram bucket: 0 for values below 1, and `log2(value) + 1`
+/// for the others.
+fn generate_log2_bucket<'a>(
+    ctx: &mut CodegenCtx<'a>,
+    value: IntValue<'a>,
+    signed: bool,
+) -> IntValue<'a> {
+    let i64_ty = ctx.llvm_context.i64_type();
+
+    let below_one = ctx.builder.build_int_compare(
+        if signed {
+            IntPredicate::SLT
+        } else {
+            IntPredicate::ULT
+        },
+        value,
+        i64_ty.const_int(1, false),
+        "below_one",
+    );
+
+    // binary search for the highest set bit; BPF doesn't have a clz instruction
+    let mut rest = value;
+    let mut log2 = i64_ty.const_zero();
+
+    for shift in [32, 16, 8, 4, 2, 1] {
+        let shift_val = i64_ty.const_int(shift, false);
+        let is_above = ctx.builder.build_int_compare(
+            IntPredicate::UGE,
+            rest,
+            i64_ty.const_int(1 << shift, false),
+            "is_above",
+        );
+
+        let shifted = ctx
+            .builder
+            .build_right_shift(rest, shift_val, false, "shifted");
+        rest = ctx
+            .builder
+            .build_select(is_above, shifted, rest, "rest")
+            .into_int_value();
+
+        let added = ctx.builder.build_int_add(log2, shift_val, "added");
+        log2 = ctx
+            .builder
+            .build_select(is_above, added, log2, "log2")
+            .into_int_value();
+    }
+
+    let bucket = ctx
+        .builder
+        .build_int_add(log2, i64_ty.const_int(1, false), "bucket");
+
+    ctx.builder
+        .build_select(below_one, i64_ty.const_zero(), bucket, "log2_bucket")
+        .into_int_value()
+}
+
+/// Returns the index of a log-linear histogram bucket: small values have a bucket each,
+/// larger ones are split into `2^LOG_LINEAR_SUB_BITS` buckets per power of 2.
+/// Values below 1 are counted in bucket 0.
+fn generate_log_linear_bucket<'a>(
+    ctx: &mut CodegenCtx<'a>,
+    value: IntValue<'a>,
+    signed: bool,
+) -> IntValue<'a> {
+    let i64_ty = ctx.llvm_context.i64_type();
+    let sub_bits = formulas::LOG_LINEAR_SUB_BITS as u64;
+    let sub_buckets = i64_ty.const_int(1 << sub_bits, false);
+
+    let log2 = generate_log2_bucket(ctx, value, signed);
+
+    let is_small = ctx.builder.build_int_compare(
+        if signed {
+            IntPredicate::SLT
+        } else {
+            IntPredicate::ULT
+        },
+        value,
+        sub_buckets,
+        "is_small",
+    );
+    let below_one =
+        ctx.builder
+            .build_int_compare(IntPredicate::EQ, log2, i64_ty.const_zero(), "below_one");
+    let small_bucket = ctx
+        .builder
+        .build_select(below_one, i64_ty.const_zero(), value, "small_bucket")
+        .into_int_value();
+
+    // (log2 - sub_bits) * sub_buckets + (value >> (log2 - sub_bits - 1)) % sub_buckets
+    // the shift is clamped for small values to avoid shifting by a negative amount
+    let shift = ctx
+        .builder
+        .build_int_sub(log2, i64_ty.const_int(sub_bits + 1, false), "shift");
+    let shift = ctx
+        .builder
+        .build_select(is_small, i64_ty.const_zero(), shift, "shift")
+        .into_int_value();
+    let shifted = ctx
+        .builder
+        .build_right_shift(value, shift, false, "shifted");
+    let sub_bucket = ctx.builder.build_and(
+        shifted,
+        i64_ty.const_int((1 << sub_bits) - 1, false),
+        "sub_bucket",
+    );
+    let base = ctx
+        .builder
+        .build_int_sub(log2, i64_ty.const_int(sub_bits, false), "base");
+    let base = ctx
+        .builder
+        .build_left_shift(base, i64_ty.const_int(sub_bits, false), "base");
+    let bucket = ctx.builder.build_int_add(base, sub_bucket, "bucket");
+
+    ctx.builder
+        .build_select(is_small, small_bucket, bucket, "log_linear_bucket")
+        .into_int_value()
+}
+
+/// Returns the index of a linear histogram bucket.
+/// The first and the last buckets are used for values out of bounds.
+fn generate_linear_bucket<'a>(
+    ctx: &mut CodegenCtx<'a>,
+    value: IntValue<'a>,
+    signed: bool,
+    buckets: LinearBuckets,
+) -> IntValue<'a> {
+    let i64_ty = ctx.llvm_context.i64_type();
+
+    let (lt, ge) = if signed {
+        (IntPredicate::SLT, IntPredicate::SGE)
+    } else {
+        (IntPredicate::ULT, IntPredicate::UGE)
+    };
+
+    let below_min =
+        ctx.builder
+            .build_int_compare(lt, value, i64_ty.const_int(buckets.min, false), "below_min");
+    let above_max =
+        ctx.builder
+            .build_int_compare(ge, value, i64_ty.const_int(buckets.max, false), "above_max");
+
+    // out of bounds values are discarded by the selects below
+    let offset = ctx
+        .builder
+        .build_int_sub(value, i64_ty.const_int(buckets.min, false), "offset");
+    let bucket =
+        ctx.builder
+            .build_int_unsigned_div(offset, i64_ty.const_int(buckets.step, false), "bucket");
+    let bucket = ctx
+        .builder
+        .build_int_add(bucket, i64_ty.const_int(1, false), "bucket");
+
+    let last_bucket = i64_ty.const_int(buckets.bucket_count() as u64 - 1, false);
+    let bucket = ctx
+        .builder
+        .build_select(above_max, last_bucket, bucket, "bucket")
+        .into_int_value();
+
+    ctx.builder
+        .build_select(below_min, i64_ty.const_zero(), bucket, "linear_bucket")
+        .into_int_value()
+}
+
+/// Looks up the aggregate state at index 0. Exits the program if the lookup fails.
+fn generate_state_lookup<'a>(ctx: &mut CodegenCtx<'a>, map: PointerValue<'a>) -> PointerValue<'a> {
+    let state_ptr_ty = ctx
+        .llvm_context
+        .i64_type()
+        .array_type(2)
+        .ptr_type(AddressSpace::Generic);
+    let zeroth_idx = ctx.llvm_context.i32_type().const_zero();
+
+    generate_map_lookup(ctx, map, zeroth_idx, state_ptr_ty)
+}
+
+fn generate_state_field<'a>(
+    ctx: &mut CodegenCtx<'a>,
+    state_ptr: PointerValue<'a>,
+    index: u64,
+) -> PointerValue<'a> {
+    let i32_ty = ctx.llvm_context.i32_type();
+
+    unsafe {
+        ctx.builder.build_in_bounds_gep(
+            state_ptr,
+            &[i32_ty.const_zero(), i32_ty.const_int(index, false)],
+            "state_field",
+        )
+    }
+}
+
+fn generate_atomic_add<'a>(ctx: &mut CodegenCtx<'a>, ptr: PointerValue<'a>, value: IntValue<'a>) {
+    ctx.builder
+        .build_atomicrmw(
+            AtomicRMWBinOp::Add,
+            ptr,
+            value,
+            AtomicOrdering::SequentiallyConsistent,
+        )
+        .expect("atomicrmw failed");
+}
+
+/// Periodically reads the aggregate state and sends its value to the node.
+pub fn spawn_aggregate_reader(
+    prog_state: &mut LoadedState,
+    node_id: NodeId,
+    func: AggregateFunc,
+    signed: bool,
+    key_ty: Option<ExprType>,
+    out_stream: MsgChannelTx,
+) -> Result<(), RuntimeError> {
+    let name = map_name(node_id);
+    let map = prog_state
+        .prog
+        .map(&name)
+        .ok_or_else(|| RuntimeError::Other(format!("aggregate map {} not found", name)))?
+        .clone();
+
+    if let Some(bucket_count) = func.bucket_count() {
+        return spawn_histogram_reader(prog_state, map, node_id, func, bucket_count, out_stream);
+    }
+
+    if let Some(key_ty) = key_ty {
+        spawn_group_reader(prog_state, map, node_id, func, signed, key_ty, out_stream);
+        return Ok(());
+    }
+
+    let drop_state = prog_state.drop_state.clone();
+    let mut reader_interval = time::interval(Duration::from_millis(500));
+
+    tokio::spawn(async move {
+        let read_states = || -> Option<Vec<AggregateState>> {
+            if is_per_cpu(func) {
+                // per-CPU maps have a separate state for each CPU
+                let array = redbpf::PerCpuArray::<AggregateState>::new(&map).ok()?;
+                array.get(0).map(|states| states.to_vec())
+            } else {
+                let array = redbpf::Array::<AggregateState>::new(&map).ok()?;
+                array.get(0).map(|state| vec![state])
+            }
+        };
+
+        let mut prev_value = None;
+
+        loop {
+            reader_interval.tick().await;
+
+            let states = if let Some(states) = read_states() {
+                states
+            } else {
+                warn!("failed to get an aggregate value");
+                continue;
+            };
+
+            let value = formulas::aggregate_value(func, signed, &states);
+
+            if value.is_some() && value != prev_value {
+                if let Err(e) = send_value(&out_stream, node_id, value.clone().unwrap()) {
+                    warn!("failed to send a message: {:?}", e);
+                    break;
+                }
+                prev_value = value;
+            }
+
+            // FIXME: find a more efficient solution
+            if *drop_state.lock().unwrap() {
+                info!("stopping aggregate reader - prog has been dropped");
+                break;
+            }
+        }
+    });
+
+    Ok(())
+}
+
+/// Histogram displayed by a node.
+#[derive(Serialize)]
+struct NodeHistogram {
+    id: NodeId,
+    buckets: Vec<HistogramBucket>,
+}
+
+/// Periodically reads histogram buckets and sends them to the node.
+fn spawn_histogram_reader(
+    prog_state: &LoadedState,
+    map: Map,
+    node_id: NodeId,
+    func: AggregateFunc,
+    bucket_count: u32,
+    out_stream: MsgChannelTx,
+) -> Result<(), RuntimeError> {
+    // the array borrows the map, so it's opened here only to report a mismatched map type
+    redbpf::Array::<u64>::new(&map)
+        .map_err(|e| RuntimeError::Other(format!("failed to open histogram map: {:?}", e)))?;
+
+    let drop_state = prog_state.drop_state.clone();
+    let mut reader_interval = time::interval(Duration::from_millis(500));
+
+    tokio::spawn(async move {
+        let buckets_array = match redbpf::Array::<u64>::new(&map) {
+            Ok(array) => array,
+            Err(e) => {
+                warn!("failed to open histogram map: {:?}", e);
+                return;
+            }
+        };
+        let mut prev_counts = Vec::new();
+
+        loop {
+            reader_interval.tick().await;
+
+            let counts = (0..bucket_count)
+                .map(|bucket| buckets_array.get(bucket).unwrap_or_default())
+                .collect::<Vec<_>>();
+
+            if counts != prev_counts {
+                let res = match func {
+                    AggregateFunc::Percentile(rank) => send_percentiles(
+                        &out_stream,
+                        node_id,
+                        rank,
+                        &formulas::interval_counts(&counts, &prev_counts),
+                    ),
+                    _ => out_stream.unbounded_send(Message {
+                        action: "histogram".to_owned(),
+                        payload: serde_json::to_string(&NodeHistogram {
+                            id: node_id,
+                            buckets: formulas::histogram_buckets(func, &counts),
+                        })
+                        .expect("failed to construct json"),
+                    }),
+                };
+
+                if let Err(e) = res {
+                    warn!("failed to send a message: {:?}", e);
+                    break;
+                }
+                prev_counts = counts;
+            }
+
+            // FIXME: find a more efficient solution
+            if *drop_state.lock().unwrap() {
+                info!("stopping histogram reader - prog has been dropped");
+                break;
+            }
+        }
+    });
+
+    Ok(())
+}
+
+/// Common percentiles of a stream displayed by a node.
+#[derive(Serialize)]
+struct NodePercentiles {
+    id: NodeId,
+    p50: u64,
+    p90: u64,
+    p99: u64,
+}
+
+/// Sends the requested percentile as the node value, followed by p50, p90, and p99.
+/// Nothing is sent if there are no values in the counted interval.
+fn send_percentiles(
+    out_stream: &MsgChannelTx,
+    node_id: NodeId,
+    rank: u32,
+    counts: &[u64],
+) -> Result<(), TrySendError<Message>> {
+    let percentile = |rank| formulas::percentile_value(counts, rank);
+
+    let (value, p50, p90, p99) = match (
+        percentile(rank as f64 / 10_000.0),
+        percentile(0.5),
+        percentile(0.9),
+        percentile(0.99),
+    ) {
+        (Some(value), Some(p50), Some(p90), Some(p99)) => (value, p50, p90, p99),
+        _ => return Ok(()),
+    };
+
+    send_value(out_stream, node_id, value.to_string())?;
+
+    out_stream.unbounded_send(Message {
+        action: "percentiles".to_owned(),
+        payload: serde_json::to_string(&NodePercentiles {
+            id: node_id,
+            p50,
+            p90,
+            p99,
+        })
+        .expect("failed to construct json"),
+    })
+}
+
+/// Table of grouped aggregate values displayed by a node.
+#[derive(Serialize)]
+struct NodeTable {
+    id: NodeId,
+    rows: Vec<GroupRow>,
+}
+
+/// Periodically reads all groups of a grouped aggregate and sends them to the node as a table.
+fn spawn_group_reader(
+    prog_state: &LoadedState,
+    map: Map,
+    node_id: NodeId,
+    func: AggregateFunc,
+    signed: bool,
+    key_ty: ExprType,
+    out_stream: MsgChannelTx,
+) {
+    let drop_state = prog_state.drop_state.clone();
+    let mut reader_interval = time::interval(Duration::from_millis(500));
+
+    tokio::spawn(async move {
+        let mut prev_rows = Vec::new();
+
+        loop {
+            reader_interval.tick().await;
+
+            let groups = if key_ty == ExprType::String {
+                read_groups::<[u8; formulas::MAX_GROUP_KEY_LEN as usize]>(&map, func, |key| {
+                    formulas::str_group_key(&key)
+                })
+            } else {
+                read_groups::<u64>(&map, func, |key| formulas::number_group_key(key_ty, key))
+            };
+
+            let groups = if let Some(groups) = groups {
+                groups
+            } else {
+                warn!("failed to read aggregate groups");
+                continue;
+            };
+
+            let rows = formulas::group_rows(func, signed, groups);
+
+            if rows != prev_rows {
+                if let Err(e) = out_stream.unbounded_send(Message {
+                    action: "table".to_owned(),
+                    payload: serde_json::to_string(&NodeTable {
+                        id: node_id,
+                        rows: rows.clone(),
+                    })
+                    .expect("failed to construct json"),
+                }) {
+                    warn!("failed to send a message: {:?}", e);
+                    break;
+                }
+                prev_rows = rows;
+            }
+
+            // FIXME: find a more efficient solution
+            if *drop_state.lock().unwrap() {
+                info!("stopping group reader - prog has been dropped");
+                break;
+            }
+        }
+    });
+}
+
+/// Reads aggregate states of all groups from a BPF hash map.
+fn read_groups<K: Clone>(
+    map: &Map,
+    func: AggregateFunc,
+    decode_key: impl Fn(K) -> Value,
+) -> Option<Vec<(Value, Vec<AggregateState>)>> {
+    let groups = if is_per_cpu(func) {
+        // per-CPU maps have a separate state for each CPU
+        redbpf::PerCpuHashMap::<K, AggregateState>::new(map)
+            .ok()?
+            .iter()
+            .map(|(key, states)| (decode_key(key), states.to_vec()))
+            .collect()
+    } else {
+        redbpf::HashMap::<K, AggregateState>::new(map)
+            .ok()?
+            .iter()
+            .map(|(key, state)| (decode_key(key), vec![state]))
+            .collect()
+    };
+
+    Some(groups)
+}
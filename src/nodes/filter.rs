@@ -1,8 +1,12 @@
 //! Generates BPF code for filtering data.
 
-use std::rc::Rc;
+use std::{cell::RefCell, rc::Rc};
 
-use crate::{codegen::CodegenError, dsl::NodeId, formulas};
+use crate::{
+    codegen::CodegenError,
+    dsl::NodeId,
+    formulas::{self, Diagnostic, ExprType},
+};
 
 use super::{CodegenCtx, Node, NodeProperties, OutputType};
 
@@ -10,7 +14,8 @@ use super::{CodegenCtx, Node, NodeProperties, OutputType};
 pub struct FilterNode {
     id: NodeId,
     props: NodeProperties,
-    output_type: Rc<OutputType>,
+    // same as the input type; known only after the type check
+    output_type: RefCell<Rc<OutputType>>,
 }
 
 impl FilterNode {
@@ -18,7 +23,34 @@ impl FilterNode {
         Self {
             id,
             props,
-            output_type: Rc::new(OutputType::Void),
+            output_type: RefCell::new(Rc::new(OutputType::Void)),
+        }
+    }
+
+    /// Checks that the filter condition is a boolean and passes the input type through.
+    pub fn typecheck(&self, inputs: &[&Node]) -> Vec<Diagnostic> {
+        // TODO: support multiple inputs?
+        let input_type = match inputs.first() {
+            Some(input) => input.output_type(),
+            None => Rc::new(OutputType::Void),
+        };
+        *self.output_type.borrow_mut() = input_type.clone();
+
+        let filter_formula = match self.props.get("value") {
+            Some(formula) => formula,
+            None => return Vec::new(),
+        };
+
+        match formulas::check_types(self.id, "value", filter_formula, Some(input_type.as_ref())) {
+            Ok(ty) if ty == ExprType::Boolean || ty.is_numeric() => Vec::new(),
+            Ok(ty) => vec![Diagnostic::new(
+                self.id,
+                "value",
+                filter_formula.span(),
+                format!("filter condition must be a boolean, found a {}", ty),
+            )
+            .with_hint("compare the value, e.g. `input.pid = 1`")],
+            Err(errors) => errors,
         }
     }
 
@@ -34,18 +66,40 @@ impl FilterNode {
         let input_node = inputs[0];
         // input_node.output_type();
 
-        let filter_formula = &self
-            .props
-            .props
-            .get("value")
-            .ok_or(CodegenError::ExpectedProperty("value"))?;
+        let filter_formula = formulas::fold_constants(
+            self.props
+                .props
+                .get("value")
+                .ok_or(CodegenError::ExpectedProperty("value"))?,
+        );
 
         let output_type = input_node.output_type();
 
-        dbg!(&output_type);
-        dbg!(filter_formula);
+        let func_exit = ctx.func_exit;
+
+        match formulas::const_condition(&filter_formula) {
+            // always true - nothing to check
+            Some(true) => {}
+            Some(false) => {
+                // always false - skip the rest of the program.
+                // code generated by the following nodes is unreachable and will be removed by LLVM
+                ctx.builder.build_unconditional_branch(func_exit);
 
-        formulas::generate_expr_code(ctx, filter_formula, Some(output_type.as_ref()));
+                let unreachable_block = ctx
+                    .llvm_context
+                    .append_basic_block(ctx.func, "filter_unreachable");
+                ctx.set_current_block(unreachable_block);
+            }
+            None => {
+                // exit early if the filter condition doesn't hold
+                formulas::generate_cond_code(
+                    ctx,
+                    &filter_formula,
+                    Some(output_type.as_ref()),
+                    func_exit,
+                )?;
+            }
+        }
 
         // self.output_type = output_type;
 
@@ -55,6 +109,6 @@ impl FilterNode {
 
     pub fn output_type(&self) -> Rc<OutputType> {
         // = same output type as my input
-        self.output_type.clone()
+        self.output_type.borrow().clone()
     }
 }
@@ -0,0 +1,71 @@
+//! Lists probe locations of a binary, so that they can be suggested in the node editor.
+
+use serde::Serialize;
+use tracing::warn;
+use usdt_reader::Context as UsdtContext;
+
+use super::symbols::{self, FunctionSymbol};
+
+/// Static probe defined in a binary.
+#[derive(Debug, Serialize)]
+pub struct UsdtProbe {
+    pub provider: String,
+    pub name: String,
+    /// Argument spec, e.g. `-4@%edi 8@%rsi`.
+    pub args: String,
+}
+
+/// Functions and USDT probes of a binary.
+#[derive(Debug, Serialize)]
+pub struct BinaryProbes {
+    pub path: String,
+    pub functions: Vec<FunctionSymbol>,
+    pub usdt_probes: Vec<UsdtProbe>,
+}
+
+/// Lists functions and USDT probes of a binary.
+/// The binary is read synchronously, so async callers should run this on a blocking thread.
+pub fn list_probes(path: &str) -> Result<BinaryProbes, String> {
+    let binary = symbols::read_binary(path)?;
+    let functions = symbols::list_functions(&binary)?;
+
+    Ok(BinaryProbes {
+        path: path.to_string(),
+        functions,
+        usdt_probes: list_usdt_probes(&binary),
+    })
+}
+
+/// Returns USDT probes defined in the ELF notes.
+/// Probes that can't be read are skipped, so that functions can be listed anyway.
+fn list_usdt_probes(binary: &[u8]) -> Vec<UsdtProbe> {
+    let context = match UsdtContext::new(binary) {
+        Ok(context) => context,
+        Err(e) => {
+            warn!("failed to read USDT probes: {:?}", e);
+            return Vec::new();
+        }
+    };
+
+    let probes = match context.probes() {
+        Ok(probes) => probes,
+        Err(e) => {
+            warn!("failed to read USDT probes: {:?}", e);
+            return Vec::new();
+        }
+    };
+
+    probes
+        .filter_map(|probe| match probe {
+            Ok(probe) => Some(UsdtProbe {
+                provider: probe.provider_name.to_string(),
+                name: probe.probe_name.to_string(),
+                args: probe.args.to_string(),
+            }),
+            Err(e) => {
+                warn!("failed to read a USDT probe: {:?}", e);
+                None
+            }
+        })
+        .collect()
+}
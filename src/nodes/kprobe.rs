@@ -0,0 +1,114 @@
+//! Implements eBPF KProbe feature.
+
+use std::rc::Rc;
+
+use tracing::info;
+
+use super::{pt_regs, uprobe::UProbeResult, CodegenCtx, Node, NodeProperties, OutputType};
+use crate::codegen::CodegenError;
+use crate::dsl::NodeId;
+use crate::formulas::{Diagnostic, ExprType};
+use crate::runtime::{LoadedState, RuntimeError};
+use crate::ws::MsgChannelTx;
+
+/// Returns whether a kernel function is a syscall entry point, e.g. `__x64_sys_openat`,
+/// which receives the syscall arguments in a `struct pt_regs` rather than in registers.
+fn is_syscall_wrapper(fn_name: &str) -> bool {
+    fn_name.starts_with("__x64_sys_")
+}
+
+#[derive(Debug)]
+pub struct KProbe {
+    id: NodeId,
+    props: NodeProperties,
+    output_type: Rc<OutputType>,
+    /// Whether the probe fires when the function returns (kretprobe).
+    is_return: bool,
+}
+
+impl KProbe {
+    pub fn new(id: NodeId, props: NodeProperties) -> Self {
+        Self::with_kind(id, props, false)
+    }
+
+    /// Creates a probe that fires when the kernel function returns.
+    pub fn new_return(id: NodeId, props: NodeProperties) -> Self {
+        Self::with_kind(id, props, true)
+    }
+
+    fn with_kind(id: NodeId, props: NodeProperties, is_return: bool) -> Self {
+        // kernel probes receive `struct pt_regs` just like uprobes
+        let mut result = UProbeResult::new(is_return).with_retval_size(&props);
+
+        let fn_name = props.eval_str("function").ok().flatten();
+        if fn_name.is_some_and(|name| is_syscall_wrapper(&name)) {
+            result = result.with_syscall_args();
+        }
+
+        Self {
+            id,
+            props,
+            output_type: Rc::new(OutputType::Struct(Box::new(result))),
+            is_return,
+        }
+    }
+
+    /// Attaches the program to the kernel function.
+    pub fn load(
+        &self,
+        state: &mut LoadedState,
+        _out_stream: MsgChannelTx,
+    ) -> Result<(), RuntimeError> {
+        let fn_name = self
+            .props
+            .eval_str("function")?
+            .ok_or(RuntimeError::ExpectedProperty("function"))?;
+
+        // the program is named after the function, see `codegen`
+        let kprobe = state.prog.kprobe_mut(&fn_name).ok_or_else(|| {
+            RuntimeError::Other(format!(
+                "expected a {} kprobe. no compiled bpf program?",
+                fn_name
+            ))
+        })?;
+
+        info!("Attaching kprobe to [{}]", fn_name);
+
+        kprobe
+            .attach_kprobe(&fn_name, 0)
+            .map_err(|e| RuntimeError::Other(format!("failed to attach kprobe: {:?}", e)))
+    }
+
+    /// Checks that the function name is a string.
+    pub fn typecheck(&self, _inputs: &[&Node]) -> Vec<Diagnostic> {
+        let mut errors = self
+            .props
+            .check_prop_types(self.id, &["function"], ExprType::String);
+
+        if self.is_return {
+            errors.append(&mut pt_regs::check_retval_size(self.id, &self.props));
+        }
+
+        errors
+    }
+
+    pub fn codegen(&self, ctx: &mut CodegenCtx, _inputs: &[&Node]) -> Result<(), CodegenError> {
+        let fn_name = self
+            .props
+            .eval_str("function")
+            .map_err(|e| CodegenError::Other(format!("{:?}", e)))?
+            .ok_or(CodegenError::ExpectedProperty("function"))?;
+
+        let kind = if self.is_return {
+            "kretprobe"
+        } else {
+            "kprobe"
+        };
+        ctx.func.set_section(&format!("{}/{}", kind, fn_name));
+        Ok(())
+    }
+
+    pub fn output_type(&self) -> Rc<OutputType> {
+        self.output_type.clone()
+    }
+}
@@ -0,0 +1,184 @@
+//! Measures function latency with a pair of entry and return uprobes.
+//!
+//! The entry probe stores a timestamp in a hash map keyed by the thread,
+//! and the return probe (the main program) computes the duration and removes the entry.
+
+use std::rc::Rc;
+
+use inkwell::{
+    values::{AnyValue, PointerValue},
+    AddressSpace,
+};
+
+use super::{
+    uprobe::UProbeResult, CodegenCtx, ExprValue, Node, NodeProperties, OutputStruct, OutputType,
+    UProbe,
+};
+use crate::{
+    codegen::{
+        bpf_map_delete_elem, bpf_map_lookup_elem, bpf_map_update_elem, bpf_prog_type,
+        call_bpf_helper, generate_bpf_map, generate_null_check, CodegenError, BPF_ANY,
+        BPF_MAP_TYPE_HASH,
+    },
+    dsl::NodeId,
+    formulas::{Diagnostic, ExprType},
+    runtime::{LoadedState, RuntimeError},
+    ws::MsgChannelTx,
+};
+
+/// Maximum number of threads that can be inside the measured function at the same time.
+const MAX_THREADS: u32 = 10240;
+
+/// Name of the BPF program attached to the function entry.
+const ENTRY_PROG: &str = "mlens_entry";
+
+#[derive(Debug)]
+pub struct Latency {
+    id: NodeId,
+    /// Return probe that shares the location properties with the entry probe.
+    probe: UProbe,
+    output_type: Rc<OutputType>,
+}
+
+impl Latency {
+    pub fn new(id: NodeId, props: NodeProperties) -> Self {
+        let probe = UProbe::new_return(id, props);
+        let output_type = Rc::new(OutputType::Struct(Box::new(LatencyResult {
+            node_id: id,
+            probe: probe.result().clone(),
+        })));
+
+        Self {
+            id,
+            probe,
+            output_type,
+        }
+    }
+
+    /// Attaches both the entry and the return probe.
+    pub fn load(
+        &self,
+        state: &mut LoadedState,
+        _out_stream: MsgChannelTx,
+    ) -> Result<(), RuntimeError> {
+        // a single entry program is attached to all matched functions
+        self.probe.attach(state, ENTRY_PROG, false)?;
+        self.probe.attach(state, "mlens", true)
+    }
+
+    /// Generates the entry program and computes the duration in the return program.
+    pub fn codegen(&self, ctx: &mut CodegenCtx, inputs: &[&Node]) -> Result<(), CodegenError> {
+        self.probe.codegen(ctx, inputs)?;
+
+        let i64_ty = ctx.llvm_context.i64_type();
+        let starts = generate_bpf_map(
+            ctx,
+            &format!("latency_{}", self.id),
+            BPF_MAP_TYPE_HASH,
+            8,
+            8,
+            MAX_THREADS,
+        )
+        .as_pointer_value();
+
+        self.codegen_entry(ctx, starts);
+
+        // pid_tgid is unique for every thread, so it's used as the key
+        ctx.builder.position_at_end(ctx.allocs_block);
+        let key = ctx.builder.build_alloca(i64_ty, "thread_key");
+
+        ctx.builder.position_at_end(ctx.current_block);
+        let pid_tgid = call_bpf_helper(ctx, 14, "pid_tgid");
+        ctx.builder.build_store(key, pid_tgid);
+
+        // the entry can be missing if the probe was attached while the function was running
+        let start_ptr =
+            bpf_map_lookup_elem(ctx, starts, key, i64_ty.ptr_type(AddressSpace::Generic));
+        generate_null_check(ctx, start_ptr);
+
+        let start = ctx.builder.build_load(start_ptr, "start").into_int_value();
+        let now = call_bpf_helper(ctx, 5, "now");
+        let duration = ctx.builder.build_int_sub(now, start, "duration_ns");
+
+        bpf_map_delete_elem(ctx, starts, key);
+
+        ctx.node_values.insert(
+            (self.id, "duration_ns"),
+            ExprValue {
+                value: duration.as_any_value_enum(),
+                ty: ExprType::Number,
+            },
+        );
+
+        Ok(())
+    }
+
+    /// Generates a program that stores the entry timestamp of the current thread.
+    fn codegen_entry<'a>(&self, ctx: &mut CodegenCtx<'a>, starts: PointerValue<'a>) {
+        let i64_ty = ctx.llvm_context.i64_type();
+
+        let func = ctx
+            .module
+            .add_function("bpf_entry", bpf_prog_type(ctx), None);
+        func.set_section(&format!("uprobe/{}", ENTRY_PROG));
+
+        let entry_block = ctx.llvm_context.append_basic_block(func, "entry");
+        ctx.builder.position_at_end(entry_block);
+
+        let key = ctx.builder.build_alloca(i64_ty, "thread_key");
+        let start = ctx.builder.build_alloca(i64_ty, "start");
+
+        let pid_tgid = call_bpf_helper(ctx, 14, "pid_tgid");
+        ctx.builder.build_store(key, pid_tgid);
+        let now = call_bpf_helper(ctx, 5, "now");
+        ctx.builder.build_store(start, now);
+
+        // overwrites a stale entry if the thread has left the function without returning
+        bpf_map_update_elem(ctx, starts, key, start, BPF_ANY);
+
+        ctx.builder
+            .build_return(Some(&ctx.llvm_context.i32_type().const_zero()));
+
+        // continue generating the return program
+        ctx.builder.position_at_end(ctx.current_block);
+    }
+
+    /// Checks the probe location properties.
+    pub fn typecheck(&self, inputs: &[&Node]) -> Vec<Diagnostic> {
+        self.probe.typecheck(inputs)
+    }
+
+    pub fn output_type(&self) -> Rc<OutputType> {
+        self.output_type.clone()
+    }
+}
+
+/// Provides the return probe properties and the measured duration.
+struct LatencyResult {
+    node_id: NodeId,
+    probe: UProbeResult,
+}
+
+impl OutputStruct for LatencyResult {
+    fn codegen_lookup<'a>(
+        &self,
+        prop_name: &str,
+        ctx: &mut CodegenCtx<'a>,
+    ) -> Result<ExprValue<'a>, CodegenError> {
+        match prop_name {
+            "duration_ns" => ctx
+                .node_values
+                .get(&(self.node_id, "duration_ns"))
+                .cloned()
+                .ok_or_else(|| CodegenError::Other("latency is not computed yet".to_string())),
+            prop => self.probe.codegen_lookup(prop, ctx),
+        }
+    }
+
+    fn property_type(&self, prop_name: &str) -> Option<ExprType> {
+        match prop_name {
+            "duration_ns" => Some(ExprType::Number),
+            prop => self.probe.property_type(prop),
+        }
+    }
+}
@@ -1,6 +1,16 @@
+mod aggregate;
 mod filter;
+mod introspect;
+mod kprobe;
 mod label;
+mod latency;
+mod pt_regs;
+mod shared_libs;
+mod symbols;
+mod tracefs;
+mod tracepoint;
 mod uprobe;
+mod usdt_args;
 
 pub use filter::FilterNode;
 use inkwell::{
@@ -8,18 +18,26 @@ use inkwell::{
     builder::Builder,
     context::Context,
     module::Module,
-    values::{AnyValueEnum, FunctionValue},
+    values::{AnyValueEnum, FunctionValue, PointerValue},
 };
+pub use introspect::list_probes;
+pub use kprobe::KProbe;
 pub use label::LabelNode;
+pub use latency::Latency;
+pub use tracepoint::Tracepoint;
 pub use uprobe::UProbe;
 
+use futures::channel::mpsc::TrySendError;
+use serde::{Deserialize, Serialize};
+
 use std::{collections::HashMap, fmt::Debug, rc::Rc};
 
 use crate::{
-    codegen::CodegenError,
-    formulas::{ExprType, FormulaExpr},
+    codegen::{CodegenError, SetMap},
+    dsl::NodeId,
+    formulas::{self, Diagnostic, ExprType, FormulaExpr},
     runtime::{LoadedState, RuntimeError},
-    ws::MsgChannelTx,
+    ws::{Message, MsgChannelTx},
 };
 
 /// Contains all context necessary for the code generator.
@@ -28,12 +46,20 @@ pub struct CodegenCtx<'a> {
     pub module: Module<'a>,
     pub builder: Builder<'a>,
     pub func: FunctionValue<'a>,
+    /// Probe context passed to the BPF program, e.g. `struct pt_regs *` for uprobes.
+    pub bpf_ctx: PointerValue<'a>,
     pub func_exit: BasicBlock<'a>,
     // FIXME: this should not be required - introduce MIR to know which allocations
     // will be needed beforehand.
     pub allocs_block: BasicBlock<'a>,
     // FIXME: see if there's an option to get the current block in the LLVM API?
     pub current_block: BasicBlock<'a>,
+    /// Hash maps used for set membership checks.
+    pub set_maps: Vec<SetMap>,
+    /// Values computed once by source nodes and read by lookups of their output properties.
+    pub node_values: HashMap<(NodeId, &'static str), ExprValue<'a>>,
+    /// Total size of read buffers allocated on the BPF stack so far.
+    pub stack_reads_len: u32,
 }
 
 impl<'a> CodegenCtx<'a> {
@@ -52,6 +78,14 @@ pub trait OutputStruct {
         prop_name: &str,
         ctx: &mut CodegenCtx<'a>,
     ) -> Result<ExprValue<'a>, CodegenError>;
+
+    /// Returns the type of a property, or `None` if there's no such property.
+    fn property_type(&self, prop_name: &str) -> Option<ExprType>;
+
+    /// Explains why a known property is not available, e.g. if it couldn't be decoded.
+    fn unavailable_reason(&self, _prop_name: &str) -> Option<String> {
+        None
+    }
 }
 
 /// Node output type.
@@ -73,6 +107,9 @@ impl Debug for OutputType {
 pub enum Node {
     Label(LabelNode),
     UProbe(UProbe),
+    Latency(Latency),
+    KProbe(KProbe),
+    Tracepoint(Tracepoint),
     Filter(FilterNode),
 }
 
@@ -86,14 +123,41 @@ impl Node {
             Node::Label(n) => n.codegen(ctx, inputs),
             Node::Filter(n) => n.codegen(ctx, inputs),
             Node::UProbe(n) => n.codegen(ctx, inputs),
+            Node::Latency(n) => n.codegen(ctx, inputs),
+            Node::KProbe(n) => n.codegen(ctx, inputs),
+            Node::Tracepoint(n) => n.codegen(ctx, inputs),
+        }
+    }
+
+    /// Checks types of the node formulas.
+    /// Must be called in the execution order, as nodes infer their output types from inputs.
+    pub fn typecheck(&self, inputs: &[&Node]) -> Vec<Diagnostic> {
+        match self {
+            Node::Label(n) => n.typecheck(inputs),
+            Node::Filter(n) => n.typecheck(inputs),
+            Node::UProbe(n) => n.typecheck(inputs),
+            Node::Latency(n) => n.typecheck(inputs),
+            Node::KProbe(n) => n.typecheck(inputs),
+            Node::Tracepoint(n) => n.typecheck(inputs),
         }
     }
 
+    /// Returns whether the node is a probe that the BPF program is attached with.
+    pub fn is_source(&self) -> bool {
+        matches!(
+            self,
+            Node::UProbe(_) | Node::Latency(_) | Node::KProbe(_) | Node::Tracepoint(_)
+        )
+    }
+
     pub fn output_type(&self) -> Rc<OutputType> {
         match self {
             Node::Label(n) => n.output_type(),
             Node::Filter(n) => n.output_type(),
             Node::UProbe(n) => n.output_type(),
+            Node::Latency(n) => n.output_type(),
+            Node::KProbe(n) => n.output_type(),
+            Node::Tracepoint(n) => n.output_type(),
         }
     }
 
@@ -104,6 +168,9 @@ impl Node {
     ) -> Result<(), RuntimeError> {
         match self {
             Node::UProbe(n) => n.load(prog, out_stream),
+            Node::Latency(n) => n.load(prog, out_stream),
+            Node::KProbe(n) => n.load(prog, out_stream),
+            Node::Tracepoint(n) => n.load(prog, out_stream),
             Node::Label(n) => n.load(prog, out_stream),
             _ => Ok(()), // other node types don't do anything during loading
         }
@@ -136,4 +203,92 @@ impl NodeProperties {
     pub fn get(&self, name: &str) -> Option<&FormulaExpr> {
         self.props.get(name)
     }
+
+    /// Evaluates a constant string property.
+    /// Returns `None` if the property is not set.
+    pub fn eval_str(&self, prop_name: &str) -> Result<Option<String>, RuntimeError> {
+        let formula = match self.get(prop_name) {
+            Some(formula) => formula,
+            None => return Ok(None),
+        };
+
+        let value = formulas::eval(formula).map_err(|e| {
+            RuntimeError::Other(format!("failed to evaluate `{}`: {:?}", prop_name, e))
+        })?;
+
+        value.into_string().map(Some).ok_or_else(|| {
+            RuntimeError::Other(format!("expected a string value for `{}`", prop_name))
+        })
+    }
+
+    /// Evaluates a constant number property.
+    /// Returns `None` if the property is not set.
+    pub fn eval_num(&self, prop_name: &str) -> Result<Option<u64>, RuntimeError> {
+        let formula = match self.get(prop_name) {
+            Some(formula) => formula,
+            None => return Ok(None),
+        };
+
+        match formulas::eval(formula) {
+            Ok(formulas::Value::Number(num)) => Ok(Some(num)),
+            Ok(_) => Err(RuntimeError::Other(format!(
+                "expected a number value for `{}`",
+                prop_name
+            ))),
+            Err(e) => Err(RuntimeError::Other(format!(
+                "failed to evaluate `{}`: {:?}",
+                prop_name, e
+            ))),
+        }
+    }
+
+    /// Checks that the given properties have the expected type, if they're set.
+    pub fn check_prop_types(
+        &self,
+        node_id: NodeId,
+        prop_names: &[&str],
+        expected: ExprType,
+    ) -> Vec<Diagnostic> {
+        let mut errors = Vec::new();
+
+        for &prop_name in prop_names {
+            let formula = match self.get(prop_name) {
+                Some(formula) => formula,
+                None => continue,
+            };
+
+            match formulas::check_types(node_id, prop_name, formula, None) {
+                Ok(ty) if ty == expected => {}
+                Ok(ty) => errors.push(Diagnostic::new(
+                    node_id,
+                    prop_name,
+                    formula.span(),
+                    format!("`{}` must be a {}, found a {}", prop_name, expected, ty),
+                )),
+                Err(mut errs) => errors.append(&mut errs),
+            }
+        }
+
+        errors
+    }
+}
+
+/// Value displayed by a node.
+#[derive(Serialize, Deserialize)]
+struct NodeValue {
+    id: NodeId,
+    value: String,
+}
+
+/// Sends a new value to be displayed by the node.
+fn send_value(
+    out_stream: &MsgChannelTx,
+    node_id: NodeId,
+    value: String,
+) -> Result<(), TrySendError<Message>> {
+    out_stream.unbounded_send(Message {
+        action: "value".to_owned(),
+        payload: serde_json::to_string(&NodeValue { id: node_id, value })
+            .expect("failed to construct json"),
+    })
 }
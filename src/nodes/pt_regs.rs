@@ -0,0 +1,188 @@
+//! Reads registers from the `struct pt_regs` probe context.
+//!
+//! Only the x86_64 layout and calling convention are supported for now.
+
+use inkwell::{values::IntValue, AddressSpace};
+
+use super::{
+    usdt_args::{ArgLocation, UsdtArg},
+    CodegenCtx, NodeProperties,
+};
+use crate::{
+    codegen::gen_bpf_helper,
+    dsl::NodeId,
+    formulas::{self, Diagnostic, ExprType},
+};
+
+/// Indices of `struct pt_regs` fields used to pass function arguments:
+/// rdi, rsi, rdx, rcx, r8, r9.
+const ARG_REGS: [u64; 6] = [14, 13, 12, 11, 9, 8];
+
+/// Indices of `struct pt_regs` fields used to pass syscall arguments:
+/// rdi, rsi, rdx, r10, r8, r9.
+const SYSCALL_ARG_REGS: [u64; 6] = [14, 13, 12, 7, 9, 8];
+
+/// Index of the rax field, which holds the return value.
+const RET_REG: u64 = 10;
+
+/// Size of the return value if `retval_size` is not set, since most C functions return `int`.
+pub const DEFAULT_RETVAL_SIZE: u32 = 4;
+
+/// Returns the index of a function argument property, e.g. 1 for `arg1`.
+pub fn arg_index(prop_name: &str) -> Option<usize> {
+    let index = prop_name.strip_prefix("arg")?;
+    (0..ARG_REGS.len()).find(|i| i.to_string() == index)
+}
+
+/// Generates a load of a function argument passed in a register.
+pub fn codegen_arg<'a>(ctx: &mut CodegenCtx<'a>, index: usize) -> IntValue<'a> {
+    codegen_reg_load(ctx, ARG_REGS[index], &format!("arg{}", index))
+}
+
+/// Generates a read of a syscall argument in a syscall wrapper, e.g. `__x64_sys_openat`.
+/// Wrappers receive a pointer to the user `struct pt_regs` as their only argument,
+/// and the syscall arguments are read from it with bpf_probe_read_kernel.
+pub fn codegen_syscall_arg<'a>(ctx: &mut CodegenCtx<'a>, index: usize) -> IntValue<'a> {
+    let i64_ty = ctx.llvm_context.i64_type();
+
+    let user_regs = codegen_reg_load(ctx, ARG_REGS[0], "user_regs");
+    let addr = ctx.builder.build_int_add(
+        user_regs,
+        i64_ty.const_int(SYSCALL_ARG_REGS[index] * 8, false),
+        "syscall_arg_addr",
+    );
+
+    // bpf_probe_read_kernel
+    codegen_probe_read(ctx, 113, addr, 8, "syscall_arg")
+}
+
+/// Returns the size of the function return value in bytes, set by the `retval_size` property.
+pub fn retval_size(props: &NodeProperties) -> Result<u32, String> {
+    match props.eval_num("retval_size") {
+        Ok(None) => Ok(DEFAULT_RETVAL_SIZE),
+        Ok(Some(size @ (1 | 2 | 4 | 8))) => Ok(size as u32),
+        Ok(Some(size)) => Err(format!(
+            "return value size must be 1, 2, 4, or 8 bytes, found {}",
+            size
+        )),
+        Err(e) => Err(format!("{:?}", e)),
+    }
+}
+
+/// Checks the `retval_size` property of a return probe.
+pub fn check_retval_size(node_id: NodeId, props: &NodeProperties) -> Vec<Diagnostic> {
+    let mut errors = props.check_prop_types(node_id, &["retval_size"], ExprType::Number);
+
+    // a non-number size has been reported already
+    if errors.is_empty() {
+        if let (Err(error), Some(formula)) = (retval_size(props), props.get("retval_size")) {
+            errors.push(Diagnostic::new(
+                node_id,
+                "retval_size",
+                formula.span(),
+                error,
+            ));
+        }
+    }
+
+    errors
+}
+
+/// Generates a load of a function return value of `size` bytes, sign-extended to 64 bits.
+pub fn codegen_retval<'a>(ctx: &mut CodegenCtx<'a>, size: u32) -> IntValue<'a> {
+    let value = codegen_reg_load(ctx, RET_REG, "retval");
+
+    // e.g. functions returning `int` set only eax, and the upper half of rax is zeroed
+    let retval_ty = ctx.llvm_context.custom_width_int_type(size * 8);
+    let value = ctx
+        .builder
+        .build_int_truncate_or_bit_cast(value, retval_ty, "retval");
+    formulas::widen_int(ctx, value, true)
+}
+
+/// Generates a read of a USDT probe argument, extended to 64 bits.
+pub fn codegen_usdt_arg<'a>(ctx: &mut CodegenCtx<'a>, arg: &UsdtArg) -> IntValue<'a> {
+    let i64_ty = ctx.llvm_context.i64_type();
+
+    let value = match arg.location {
+        ArgLocation::Const(value) => return i64_ty.const_int(value as u64, arg.signed),
+        ArgLocation::Register(field) => codegen_reg_load(ctx, field, "usdt_arg"),
+        ArgLocation::Memory { reg, offset } => {
+            let base = codegen_reg_load(ctx, reg, "usdt_arg_base");
+            let addr = ctx.builder.build_int_add(
+                base,
+                i64_ty.const_int(offset as u64, true),
+                "usdt_arg_addr",
+            );
+            // bpf_probe_read_user
+            codegen_probe_read(ctx, 112, addr, arg.size, "usdt_arg")
+        }
+    };
+
+    // registers and memory can contain garbage above the argument size
+    let arg_ty = ctx.llvm_context.custom_width_int_type(arg.size * 8);
+    let value = ctx
+        .builder
+        .build_int_truncate_or_bit_cast(value, arg_ty, "usdt_arg");
+    formulas::widen_int(ctx, value, arg.signed)
+}
+
+/// Generates a read of `size` bytes of memory with a bpf_probe_read_* helper, which takes
+/// the destination, the size, and the source address. Returns 0 if the read fails.
+fn codegen_probe_read<'a>(
+    ctx: &mut CodegenCtx<'a>,
+    helper_num: u32,
+    addr: IntValue<'a>,
+    size: u32,
+    name: &str,
+) -> IntValue<'a> {
+    let i64_ty = ctx.llvm_context.i64_type();
+    let i32_ty = ctx.llvm_context.i32_type();
+    let i8_ptr_ty = ctx.llvm_context.i8_type().ptr_type(AddressSpace::Generic);
+
+    ctx.builder.position_at_end(ctx.allocs_block);
+    let buf = ctx.builder.build_alloca(i64_ty, name);
+    ctx.builder.position_at_end(ctx.current_block);
+    ctx.builder.build_store(buf, i64_ty.const_zero());
+
+    let bpf_probe_read = gen_bpf_helper(
+        ctx,
+        helper_num,
+        i64_ty.fn_type(
+            &[
+                i8_ptr_ty.into(), // dst
+                i32_ty.into(),    // size
+                i8_ptr_ty.into(), // unsafe_ptr
+            ],
+            false,
+        ),
+    );
+    let dst = ctx.builder.build_pointer_cast(buf, i8_ptr_ty, "dst");
+    let src = ctx.builder.build_int_to_ptr(addr, i8_ptr_ty, "src");
+    ctx.builder.build_call(
+        bpf_probe_read,
+        &[
+            dst.into(),
+            i32_ty.const_int(size as u64, false).into(),
+            src.into(),
+        ],
+        "probe_read",
+    );
+
+    ctx.builder.build_load(buf, name).into_int_value()
+}
+
+/// Generates a load of a `struct pt_regs` field; all fields are 64-bit.
+fn codegen_reg_load<'a>(ctx: &mut CodegenCtx<'a>, field: u64, name: &str) -> IntValue<'a> {
+    let i64_ty = ctx.llvm_context.i64_type();
+
+    let regs =
+        ctx.builder
+            .build_pointer_cast(ctx.bpf_ctx, i64_ty.ptr_type(AddressSpace::Generic), "regs");
+    let reg_ptr = unsafe {
+        ctx.builder
+            .build_in_bounds_gep(regs, &[i64_ty.const_int(field, false)], "reg_ptr")
+    };
+
+    ctx.builder.build_load(reg_ptr, name).into_int_value()
+}
@@ -0,0 +1,101 @@
+//! Resolves shared library names, e.g. `libc`, to paths.
+
+use std::path::Path;
+use std::process::Command;
+
+/// Resolves a library to its path.
+/// If `pid` is set, the library is looked up in the libraries mapped by the process,
+/// otherwise it's looked up in the ld.so cache.
+pub fn resolve_library(library: &str, pid: Option<u64>) -> Result<String, String> {
+    match pid {
+        Some(pid) => {
+            let maps = std::fs::read_to_string(format!("/proc/{}/maps", pid))
+                .map_err(|e| format!("failed to read memory maps of process {}: {}", pid, e))?;
+
+            find_in_maps(&maps, library)
+                .ok_or_else(|| format!("library {} is not loaded by process {}", library, pid))
+        }
+        None => {
+            let output = Command::new("ldconfig")
+                .arg("-p")
+                .output()
+                .map_err(|e| format!("failed to read the ld.so cache: {}", e))?;
+
+            find_in_ld_cache(&String::from_utf8_lossy(&output.stdout), library)
+                .ok_or_else(|| format!("library {} not found in the ld.so cache", library))
+        }
+    }
+}
+
+/// Checks if the file name of a library path matches the name, e.g. `libc` or `libc.so.6`.
+fn matches_library(path: &str, library: &str) -> bool {
+    let file_name = match Path::new(path).file_name().and_then(|name| name.to_str()) {
+        Some(file_name) => file_name,
+        None => return false,
+    };
+
+    file_name == library
+        || file_name
+            .strip_prefix(library)
+            .is_some_and(|suffix| suffix.starts_with(".so") || suffix.starts_with('-'))
+}
+
+/// Finds a library in the contents of `/proc/<pid>/maps`.
+fn find_in_maps(maps: &str, library: &str) -> Option<String> {
+    maps.lines()
+        // address, permissions, offset, device, inode, path
+        .filter_map(|line| line.split_whitespace().nth(5))
+        .find(|path| path.starts_with('/') && matches_library(path, library))
+        .map(str::to_string)
+}
+
+/// Finds a library in the output of `ldconfig -p`, preferring 64-bit libraries.
+fn find_in_ld_cache(output: &str, library: &str) -> Option<String> {
+    // e.g. `libc.so.6 (libc6,x86-64) => /lib/x86_64-linux-gnu/libc.so.6`
+    let entries = output
+        .lines()
+        .filter_map(|line| line.trim().split_once(" => "))
+        .filter(|(_, path)| matches_library(path, library))
+        .collect::<Vec<_>>();
+
+    entries
+        .iter()
+        .find(|(desc, _)| desc.contains("x86-64"))
+        .or_else(|| entries.first())
+        .map(|(_, path)| path.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_library() {
+        let maps = "\
+55d0c2a4f000-55d0c2a51000 r--p 00000000 fd:01 1234 /usr/bin/cat
+7f2b1c000000-7f2b1c022000 r--p 00000000 fd:01 5678 /usr/lib/x86_64-linux-gnu/libcrypto.so.3
+7f2b1c200000-7f2b1c228000 r--p 00000000 fd:01 9012 /usr/lib/x86_64-linux-gnu/libc.so.6
+7ffd5a1d6000-7ffd5a1f7000 rw-p 00000000 00:00 0 [stack]
+";
+        assert_eq!(
+            find_in_maps(maps, "libc").as_deref(),
+            Some("/usr/lib/x86_64-linux-gnu/libc.so.6")
+        );
+        assert_eq!(find_in_maps(maps, "libssl"), None);
+
+        let ld_cache = "\
+1234 libs found in cache `/etc/ld.so.cache'
+	libc.so.6 (libc6) => /lib32/libc.so.6
+	libc.so.6 (libc6,x86-64) => /lib/x86_64-linux-gnu/libc.so.6
+	libcap.so.2 (libc6,x86-64) => /lib/x86_64-linux-gnu/libcap.so.2
+";
+        assert_eq!(
+            find_in_ld_cache(ld_cache, "libc").as_deref(),
+            Some("/lib/x86_64-linux-gnu/libc.so.6")
+        );
+        assert_eq!(
+            find_in_ld_cache(ld_cache, "libcap.so.2").as_deref(),
+            Some("/lib/x86_64-linux-gnu/libcap.so.2")
+        );
+    }
+}
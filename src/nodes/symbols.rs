@@ -0,0 +1,184 @@
+//! Finds functions in ELF binaries by name patterns.
+
+use std::{fs::File, io::Read};
+
+use goblin::elf::{
+    section_header::SHN_UNDEF,
+    sym::{Sym, STB_GLOBAL, STB_WEAK, STT_FUNC},
+    Elf,
+};
+use regex::Regex;
+use serde::Serialize;
+
+/// Maximum size of a binary that can be searched, since it's read into memory as a whole.
+const MAX_BINARY_SIZE: u64 = 512 * 1024 * 1024;
+
+/// Function defined in a binary.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FunctionSymbol {
+    pub name: String,
+    /// Virtual address of the function in the binary.
+    pub address: u64,
+}
+
+/// Pattern that selects functions to probe.
+#[derive(Debug)]
+pub enum FunctionPattern {
+    /// Exact symbol name.
+    Exact(String),
+    /// Glob with `*` and `?` wildcards, e.g. `ssl_*`.
+    Glob(String),
+    /// Regular expression enclosed in slashes, e.g. `/^pg_.*read$/`.
+    Regex(Regex),
+}
+
+impl FunctionPattern {
+    pub fn parse(pattern: &str) -> Result<Self, String> {
+        let regex = pattern
+            .strip_prefix('/')
+            .and_then(|pattern| pattern.strip_suffix('/'));
+
+        if let Some(regex) = regex {
+            Regex::new(regex)
+                .map(Self::Regex)
+                .map_err(|e| format!("invalid regular expression: {}", e))
+        } else if pattern.contains(['*', '?']) {
+            Ok(Self::Glob(pattern.to_string()))
+        } else {
+            Ok(Self::Exact(pattern.to_string()))
+        }
+    }
+
+    pub fn matches(&self, name: &str) -> bool {
+        match self {
+            Self::Exact(pattern) => pattern == name,
+            Self::Glob(pattern) => glob_match(pattern.as_bytes(), name.as_bytes()),
+            Self::Regex(regex) => regex.is_match(name),
+        }
+    }
+}
+
+/// Matches a name against a glob, where `*` matches any sequence and `?` matches any byte.
+fn glob_match(pattern: &[u8], name: &[u8]) -> bool {
+    let (mut p, mut n) = (0, 0);
+    // position of the last `*` and the name position it has matched up to
+    let mut star = None;
+
+    while n < name.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                star = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == b'?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            // let the last `*` match one more byte
+            _ => match star {
+                Some((star_p, star_n)) => {
+                    star = Some((star_p, star_n + 1));
+                    p = star_p + 1;
+                    n = star_n + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+/// Reads a binary, rejecting paths that are not regular files, e.g. `/dev/zero` or FIFOs.
+pub fn read_binary(path: &str) -> Result<Vec<u8>, String> {
+    let read_error = |e| format!("failed to read {}: {}", path, e);
+
+    // checked before opening, since opening a FIFO blocks until there's a writer
+    let metadata = std::fs::metadata(path).map_err(read_error)?;
+    if !metadata.is_file() {
+        return Err(format!("{} is not a regular file", path));
+    }
+    if metadata.len() > MAX_BINARY_SIZE {
+        return Err(format!(
+            "{} is larger than {} MiB",
+            path,
+            MAX_BINARY_SIZE / 1024 / 1024
+        ));
+    }
+
+    // the file can grow after the check, so the read is bounded as well
+    let mut binary = Vec::with_capacity(metadata.len() as usize);
+    File::open(path)
+        .and_then(|file| file.take(MAX_BINARY_SIZE).read_to_end(&mut binary))
+        .map_err(read_error)?;
+
+    Ok(binary)
+}
+
+/// Returns global and weak functions defined in the binary, sorted by name.
+/// Both `.symtab` and `.dynsym` are searched, so stripped binaries can be probed too.
+/// Local (`static`) functions are skipped, since their names aren't unique within a binary.
+pub fn list_functions(binary: &[u8]) -> Result<Vec<FunctionSymbol>, String> {
+    let elf = Elf::parse(binary).map_err(|e| format!("failed to parse the binary: {}", e))?;
+
+    let symtab = elf.syms.iter().map(|sym| (sym, &elf.strtab));
+    let dynsym = elf.dynsyms.iter().map(|sym| (sym, &elf.dynstrtab));
+
+    let mut functions = symtab
+        .chain(dynsym)
+        .filter(|(sym, _)| is_defined_global_function(sym))
+        .filter_map(|(sym, strtab)| {
+            strtab.get_at(sym.st_name).map(|name| FunctionSymbol {
+                name: name.to_string(),
+                address: sym.st_value,
+            })
+        })
+        .collect::<Vec<_>>();
+
+    functions.sort_by(|a, b| a.name.cmp(&b.name));
+    // exported functions are present in both tables
+    functions.dedup_by(|a, b| a.name == b.name);
+    Ok(functions)
+}
+
+fn is_defined_global_function(sym: &Sym) -> bool {
+    sym.st_type() == STT_FUNC
+        && matches!(sym.st_bind(), STB_GLOBAL | STB_WEAK)
+        // undefined symbols are imported from other binaries
+        && sym.st_shndx != SHN_UNDEF as usize
+        && sym.st_value != 0
+}
+
+/// Returns sorted names of functions defined in the binary that match the pattern.
+pub fn find_functions(binary: &[u8], pattern: &FunctionPattern) -> Result<Vec<String>, String> {
+    Ok(list_functions(binary)?
+        .into_iter()
+        .map(|function| function.name)
+        .filter(|name| pattern.matches(name))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_function_pattern() {
+        let glob = FunctionPattern::parse("ssl_*_r?ad").unwrap();
+        assert!(matches!(glob, FunctionPattern::Glob(_)));
+        assert!(glob.matches("ssl_sock_read"));
+        assert!(glob.matches("ssl__read"));
+        assert!(!glob.matches("ssl_sock_write"));
+        assert!(!glob.matches("ssl_sock_read_ex"));
+
+        let regex = FunctionPattern::parse("/^pg_.*read$/").unwrap();
+        assert!(regex.matches("pg_file_read"));
+        assert!(!regex.matches("pg_file_write"));
+
+        let exact = FunctionPattern::parse("readline").unwrap();
+        assert!(exact.matches("readline"));
+        assert!(!exact.matches("readline_internal"));
+
+        assert!(FunctionPattern::parse("/pg_(/").is_err());
+    }
+}
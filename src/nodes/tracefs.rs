@@ -0,0 +1,156 @@
+//! Reads kernel tracepoint formats from tracefs.
+
+use crate::formulas::{ExprType, MAX_READ_LEN};
+
+/// Directories where tracefs is usually mounted.
+const TRACEFS_DIRS: [&str; 2] = ["/sys/kernel/tracing", "/sys/kernel/debug/tracing"];
+
+/// Field of a tracepoint record.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TracepointField {
+    pub name: String,
+    /// Offset from the start of the record in bytes.
+    pub offset: u32,
+    pub size: u32,
+    pub kind: FieldKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FieldKind {
+    /// Integer or pointer with the size of 1, 2, 4, or 8 bytes.
+    Int { signed: bool },
+    /// Fixed-size `char` array, e.g. `char comm[16]`, of up to `MAX_READ_LEN` bytes.
+    CharArray,
+}
+
+impl TracepointField {
+    pub fn expr_type(&self) -> ExprType {
+        match self.kind {
+            FieldKind::Int { signed: true } => ExprType::SignedNumber,
+            FieldKind::Int { signed: false } => ExprType::Number,
+            FieldKind::CharArray => ExprType::String,
+        }
+    }
+}
+
+/// Reads and parses the format of a tracepoint, e.g. `sched:sched_switch`.
+pub fn read_format(category: &str, name: &str) -> Result<Vec<TracepointField>, String> {
+    let format = TRACEFS_DIRS
+        .iter()
+        .find_map(|dir| {
+            std::fs::read_to_string(format!("{}/events/{}/{}/format", dir, category, name)).ok()
+        })
+        .ok_or_else(|| {
+            format!(
+                "tracepoint {}:{} not found. is tracefs mounted?",
+                category, name
+            )
+        })?;
+
+    Ok(parse_format(&format))
+}
+
+/// Parses a tracepoint `format` file.
+/// Fields that can't be represented in formulas, e.g. dynamic arrays, are skipped.
+pub fn parse_format(format: &str) -> Vec<TracepointField> {
+    format.lines().filter_map(parse_field).collect()
+}
+
+/// Parses a field description, e.g.
+/// `field:pid_t prev_pid; offset:24; size:4; signed:1;`.
+fn parse_field(line: &str) -> Option<TracepointField> {
+    let mut decl = None;
+    let mut offset = None;
+    let mut size = None;
+    let mut signed = false;
+
+    for part in line.trim().split(';') {
+        match part.trim().split_once(':') {
+            Some(("field", value)) => decl = Some(value.trim()),
+            Some(("offset", value)) => offset = value.parse().ok(),
+            Some(("size", value)) => size = value.parse().ok(),
+            Some(("signed", value)) => signed = value == "1",
+            _ => {}
+        }
+    }
+
+    let (decl, offset, size) = (decl?, offset?, size?);
+
+    // dynamic arrays store an offset and a length of the data instead of the data itself
+    if decl.starts_with("__data_loc") {
+        return None;
+    }
+
+    let (decl, kind) = match decl.split_once('[') {
+        // same size limit as `buf()`, since the array is copied into a buffer as well
+        Some((decl, _))
+            if decl.split_whitespace().rev().nth(1) == Some("char") && size <= MAX_READ_LEN =>
+        {
+            (decl, FieldKind::CharArray)
+        }
+        Some(_) => return None,
+        None if matches!(size, 1 | 2 | 4 | 8) => {
+            let is_pointer = decl.contains('*');
+            (
+                decl,
+                FieldKind::Int {
+                    signed: signed && !is_pointer,
+                },
+            )
+        }
+        None => return None,
+    };
+
+    let name = decl.split_whitespace().last()?.trim_start_matches('*');
+
+    Some(TracepointField {
+        name: name.to_string(),
+        offset,
+        size,
+        kind,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_format() {
+        let format = "name: sys_enter_openat
+ID: 650
+format:
+	field:unsigned short common_type;	offset:0;	size:2;	signed:0;
+	field:int common_pid;	offset:4;	size:4;	signed:1;
+
+	field:int __syscall_nr;	offset:8;	size:4;	signed:1;
+	field:const char * filename;	offset:24;	size:8;	signed:0;
+	field:char prev_comm[16];	offset:32;	size:16;	signed:0;
+	field:char huge[8192];	offset:100;	size:8192;	signed:0;
+	field:__data_loc char[] name;	offset:48;	size:4;	signed:0;
+	field:unsigned long args[6];	offset:52;	size:48;	signed:0;
+
+print fmt: \"filename: 0x%08lx\", ((unsigned long)(REC->filename))
+";
+
+        let fields = parse_format(format);
+        let names = fields.iter().map(|f| f.name.as_str()).collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            [
+                "common_type",
+                "common_pid",
+                "__syscall_nr",
+                "filename",
+                "prev_comm"
+            ]
+        );
+
+        assert_eq!(fields[1].expr_type(), ExprType::SignedNumber);
+        assert_eq!(
+            (fields[3].offset, fields[3].size, fields[3].expr_type()),
+            (24, 8, ExprType::Number)
+        );
+        assert_eq!((fields[4].size, fields[4].kind), (16, FieldKind::CharArray));
+    }
+}
@@ -0,0 +1,223 @@
+//! Implements kernel tracepoint source nodes.
+
+use std::rc::Rc;
+
+use inkwell::{values::AnyValue, AddressSpace};
+use tracing::info;
+
+use super::{
+    tracefs::{self, FieldKind, TracepointField},
+    uprobe::UProbeResult,
+    CodegenCtx, ExprValue, Node, NodeProperties, OutputStruct, OutputType,
+};
+use crate::codegen::CodegenError;
+use crate::dsl::NodeId;
+use crate::formulas::{self, Diagnostic, ExprType, Span};
+use crate::runtime::{LoadedState, RuntimeError};
+use crate::ws::MsgChannelTx;
+
+#[derive(Debug)]
+pub struct Tracepoint {
+    id: NodeId,
+    props: NodeProperties,
+    output_type: Rc<OutputType>,
+    /// Error encountered while reading the tracepoint format.
+    format_error: Option<String>,
+}
+
+impl Tracepoint {
+    /// Creates a tracepoint node. The record format is read from tracefs right away,
+    /// since it defines the output type required for type checking.
+    pub fn new(id: NodeId, props: NodeProperties) -> Self {
+        let (fields, format_error) = match Self::read_format(&props) {
+            Ok(fields) => (fields, None),
+            Err(e) => (Vec::new(), Some(e)),
+        };
+
+        Self {
+            id,
+            props,
+            output_type: Rc::new(OutputType::Struct(Box::new(TracepointResult { fields }))),
+            format_error,
+        }
+    }
+
+    fn read_format(props: &NodeProperties) -> Result<Vec<TracepointField>, String> {
+        let (category, name) = Self::tracepoint_name(props).map_err(|e| format!("{:?}", e))?;
+        tracefs::read_format(&category, &name)
+    }
+
+    /// Returns the category and the name of the tracepoint, e.g. `sched` and `sched_switch`.
+    fn tracepoint_name(props: &NodeProperties) -> Result<(String, String), RuntimeError> {
+        let tracepoint = props
+            .eval_str("tracepoint")?
+            .ok_or(RuntimeError::ExpectedProperty("tracepoint"))?;
+
+        match tracepoint.split_once(':') {
+            Some((category, name)) => Ok((category.to_string(), name.to_string())),
+            None => Err(RuntimeError::Other(format!(
+                "expected a `category:name` tracepoint, found `{}`",
+                tracepoint
+            ))),
+        }
+    }
+
+    /// Attaches the program to the tracepoint.
+    pub fn load(
+        &self,
+        state: &mut LoadedState,
+        _out_stream: MsgChannelTx,
+    ) -> Result<(), RuntimeError> {
+        let (category, name) = Self::tracepoint_name(&self.props)?;
+
+        let tracepoint = state.prog.trace_point_mut("mlens").ok_or_else(|| {
+            RuntimeError::Other("expected tracepoint/mlens. no compiled bpf program?".to_string())
+        })?;
+
+        info!("Attaching to tracepoint {}:{}", category, name);
+
+        tracepoint
+            .attach_trace_point(&category, &name)
+            .map_err(|e| RuntimeError::Other(format!("failed to attach tracepoint: {:?}", e)))
+    }
+
+    /// Checks that the tracepoint is set and exists.
+    pub fn typecheck(&self, _inputs: &[&Node]) -> Vec<Diagnostic> {
+        let mut errors = self
+            .props
+            .check_prop_types(self.id, &["tracepoint"], ExprType::String);
+
+        // a non-string name has been reported already
+        if errors.is_empty() {
+            match (&self.format_error, self.props.get("tracepoint")) {
+                (_, None) => errors.push(
+                    Diagnostic::new(
+                        self.id,
+                        "tracepoint",
+                        Span::default(),
+                        "`tracepoint` is not set".to_string(),
+                    )
+                    .with_hint("set a `category:name` tracepoint, e.g. `\"sched:sched_switch\"`"),
+                ),
+                (Some(error), Some(formula)) => errors.push(Diagnostic::new(
+                    self.id,
+                    "tracepoint",
+                    formula.span(),
+                    error.clone(),
+                )),
+                (None, Some(_)) => {}
+            }
+        }
+
+        errors
+    }
+
+    pub fn codegen(&self, ctx: &mut CodegenCtx, _inputs: &[&Node]) -> Result<(), CodegenError> {
+        ctx.func.set_section("tracepoint/mlens");
+        Ok(())
+    }
+
+    pub fn output_type(&self) -> Rc<OutputType> {
+        self.output_type.clone()
+    }
+}
+
+/// Provides the fields of a tracepoint record.
+struct TracepointResult {
+    fields: Vec<TracepointField>,
+}
+
+impl TracepointResult {
+    fn field(&self, name: &str) -> Option<&TracepointField> {
+        self.fields.iter().find(|field| field.name == name)
+    }
+
+    /// Generates a read of a record field.
+    fn codegen_field<'a>(
+        &self,
+        ctx: &mut CodegenCtx<'a>,
+        field: &TracepointField,
+    ) -> Result<ExprValue<'a>, CodegenError> {
+        let i8_ty = ctx.llvm_context.i8_type();
+        let field_ptr = unsafe {
+            ctx.builder.build_in_bounds_gep(
+                ctx.bpf_ctx,
+                &[ctx
+                    .llvm_context
+                    .i64_type()
+                    .const_int(field.offset as u64, false)],
+                "field_ptr",
+            )
+        };
+
+        let value = match field.kind {
+            FieldKind::Int { signed } => {
+                let int_ty = ctx.llvm_context.custom_width_int_type(field.size * 8);
+                let ptr = ctx.builder.build_pointer_cast(
+                    field_ptr,
+                    int_ty.ptr_type(AddressSpace::Generic),
+                    "int_field_ptr",
+                );
+                let value = ctx.builder.build_load(ptr, &field.name).into_int_value();
+
+                let i64_ty = ctx.llvm_context.i64_type();
+                let value = if field.size == 8 {
+                    value
+                } else if signed {
+                    ctx.builder.build_int_s_extend(value, i64_ty, "field_ext")
+                } else {
+                    ctx.builder.build_int_z_extend(value, i64_ty, "field_ext")
+                };
+                value.as_any_value_enum()
+            }
+            FieldKind::CharArray => {
+                // copy the string to a buffer, so it can be passed to helpers
+                let array_ty = i8_ty.array_type(field.size);
+                let buf = formulas::generate_read_buffer(ctx, array_ty);
+
+                ctx.builder
+                    .build_memcpy(
+                        buf,
+                        1,
+                        field_ptr,
+                        1,
+                        ctx.llvm_context
+                            .i64_type()
+                            .const_int(field.size as u64, false),
+                    )
+                    .map_err(|e| CodegenError::Other(e.to_string()))?;
+                buf.as_any_value_enum()
+            }
+        };
+
+        Ok(ExprValue {
+            value,
+            ty: field.expr_type(),
+        })
+    }
+}
+
+impl OutputStruct for TracepointResult {
+    fn codegen_lookup<'a>(
+        &self,
+        prop_name: &str,
+        ctx: &mut CodegenCtx<'a>,
+    ) -> Result<ExprValue<'a>, CodegenError> {
+        // record fields shadow the properties provided by helpers
+        match (self.field(prop_name), prop_name) {
+            (Some(field), _) => self.codegen_field(ctx, field),
+            (None, "process_name") => Ok(UProbeResult::new(false).codegen_proc_name(ctx)),
+            (None, "pid") => Ok(UProbeResult::new(false).codegen_pid(ctx)),
+            (None, prop) => Err(CodegenError::Other(format!("unknown property {}", prop))),
+        }
+    }
+
+    fn property_type(&self, prop_name: &str) -> Option<ExprType> {
+        match (self.field(prop_name), prop_name) {
+            (Some(field), _) => Some(field.expr_type()),
+            (None, "process_name") => Some(ExprType::String),
+            (None, "pid") => Some(ExprType::Number),
+            _ => None,
+        }
+    }
+}
@@ -2,22 +2,79 @@
 
 use std::rc::Rc;
 
-use inkwell::{values::AnyValue, AddressSpace};
+use inkwell::{
+    attributes::{Attribute, AttributeLoc},
+    module::Linkage,
+    values::AnyValue,
+    AddressSpace,
+};
 use tracing::info;
 use usdt_reader::Context as UsdtContext;
 
-use super::{CodegenCtx, ExprValue, Node, NodeProperties, OutputStruct, OutputType};
-use crate::codegen::{gen_bpf_helper, CodegenError};
+use super::{
+    pt_regs, shared_libs,
+    symbols::{self, FunctionPattern},
+    usdt_args::{self, UsdtArg},
+    CodegenCtx, ExprValue, Node, NodeProperties, OutputStruct, OutputType,
+};
+use crate::codegen::{bpf_prog_type, gen_bpf_helper, generate_string_literal, CodegenError};
 use crate::dsl::NodeId;
-use crate::formulas::ExprType;
+use crate::formulas::{Diagnostic, ExprType};
 use crate::runtime::{LoadedState, RuntimeError};
 use crate::ws::MsgChannelTx;
 
+/// Maximum number of functions a `function` pattern can match;
+/// each function gets its own BPF program.
+const MAX_FUNCTION_MATCHES: usize = 64;
+
+/// Matched function names longer than this are truncated in `input.function`.
+const MAX_FUNCTION_NAME_LEN: usize = 64;
+
 #[derive(Debug)]
 struct UProbePoint {
     fn_name: Option<String>,
     offset: u64,
     semaphore_offset: u64,
+    /// USDT argument spec, e.g. `-4@%edi 8@%rsi`. Empty for function probes.
+    arg_spec: String,
+}
+
+impl UProbePoint {
+    fn function(name: String) -> Self {
+        Self {
+            fn_name: Some(name),
+            offset: 0,
+            semaphore_offset: 0,
+            arg_spec: String::new(),
+        }
+    }
+}
+
+/// Probe locations, resolved once when the node is created.
+#[derive(Debug)]
+struct ProbeLocations {
+    binary_path: String,
+    points: Vec<UProbePoint>,
+    /// Whether the points are functions matched by a pattern, each with its own BPF program.
+    per_function: bool,
+}
+
+/// Error encountered while resolving the probe locations.
+#[derive(Debug)]
+struct LocationError {
+    /// Property the error is reported on by the type checker, if it's set.
+    property: &'static str,
+    message: String,
+}
+
+impl LocationError {
+    fn new(property: &'static str, error: RuntimeError) -> Self {
+        let message = match error {
+            RuntimeError::Other(message) => message,
+            error => format!("{:?}", error),
+        };
+        Self { property, message }
+    }
 }
 
 #[derive(Debug)]
@@ -25,57 +82,211 @@ pub struct UProbe {
     id: NodeId,
     props: NodeProperties,
     output_type: Rc<OutputType>,
-    trace_point: Option<UProbePoint>,
+    /// Whether the probe fires when the function returns (uretprobe).
+    is_return: bool,
+    locations: Result<ProbeLocations, LocationError>,
+    result: UProbeResult,
 }
 
 impl UProbe {
     pub fn new(id: NodeId, props: NodeProperties) -> Self {
+        Self::with_kind(id, props, false)
+    }
+
+    /// Creates a probe that fires when the function returns.
+    pub fn new_return(id: NodeId, props: NodeProperties) -> Self {
+        Self::with_kind(id, props, true)
+    }
+
+    /// Creates a probe and resolves its locations.
+    /// This reads the probed binary, so it must not be called on an async executor thread.
+    fn with_kind(id: NodeId, props: NodeProperties, is_return: bool) -> Self {
+        let locations = Self::resolve_locations(&props);
+
+        let mut result = UProbeResult::new(is_return).with_retval_size(&props);
+        match (props.get("probe"), &locations) {
+            (Some(_), _) => {
+                result.function = ProbeFunction::None;
+                if !is_return {
+                    result.args = ProbeArgs::Usdt(Self::usdt_args(&locations));
+                }
+            }
+            (None, Ok(locations)) if locations.per_function => {
+                result.function = ProbeFunction::Matched(
+                    locations
+                        .points
+                        .iter()
+                        .filter_map(|point| point.fn_name.clone())
+                        .collect(),
+                );
+            }
+            (None, _) => match props.eval_str("function") {
+                Ok(Some(name)) if Self::is_pattern(&name) => {
+                    // the pattern error is reported by the type checker
+                    result.function = ProbeFunction::Matched(Vec::new());
+                }
+                Ok(Some(name)) => result.function = ProbeFunction::Exact(name),
+                _ => {}
+            },
+        }
+
         Self {
             id,
             props,
-            output_type: Rc::new(OutputType::Struct(Box::new(UProbeResult {}))),
-            trace_point: None,
+            output_type: Rc::new(OutputType::Struct(Box::new(result.clone()))),
+            is_return,
+            locations,
+            result,
+        }
+    }
+
+    /// Returns the output of the probe.
+    pub(super) fn result(&self) -> &UProbeResult {
+        &self.result
+    }
+
+    fn is_pattern(function: &str) -> bool {
+        !matches!(
+            FunctionPattern::parse(function),
+            Ok(FunctionPattern::Exact(_))
+        )
+    }
+
+    /// Finds the probed binary and the probe locations in it.
+    fn resolve_locations(props: &NodeProperties) -> Result<ProbeLocations, LocationError> {
+        let binary_path = Self::binary_path(props).map_err(|e| match props.get("library") {
+            Some(_) => LocationError::new("library", e),
+            None => LocationError::new("program", e),
+        })?;
+
+        if let Some(probe_name) = props
+            .eval_str("probe")
+            .map_err(|e| LocationError::new("probe", e))?
+        {
+            return Ok(ProbeLocations {
+                points: Self::usdt_points(&binary_path, &probe_name)
+                    .map_err(|e| LocationError::new("probe", e))?,
+                binary_path,
+                per_function: false,
+            });
+        }
+
+        let function = props
+            .eval_str("function")
+            .and_then(|function| function.ok_or(RuntimeError::ExpectedProperty("function")))
+            .map_err(|e| LocationError::new("function", e))?;
+
+        let function_error = |message| LocationError {
+            property: "function",
+            message,
+        };
+        match FunctionPattern::parse(&function).map_err(function_error)? {
+            FunctionPattern::Exact(name) => Ok(ProbeLocations {
+                binary_path,
+                points: vec![UProbePoint::function(name)],
+                per_function: false,
+            }),
+            pattern => Ok(ProbeLocations {
+                points: Self::match_functions(&binary_path, &pattern)
+                    .map_err(function_error)?
+                    .into_iter()
+                    .map(UProbePoint::function)
+                    .collect(),
+                binary_path,
+                per_function: true,
+            }),
         }
     }
 
+    /// Finds functions matching a glob or a regex pattern.
+    fn match_functions(
+        binary_path: &str,
+        pattern: &FunctionPattern,
+    ) -> Result<Vec<String>, String> {
+        let binary = symbols::read_binary(binary_path)?;
+
+        match symbols::find_functions(&binary, pattern)? {
+            names if names.is_empty() => Err("no functions match the pattern".to_string()),
+            names if names.len() > MAX_FUNCTION_MATCHES => Err(format!(
+                "the pattern matches {} functions, at most {} are supported",
+                names.len(),
+                MAX_FUNCTION_MATCHES
+            )),
+            names => Ok(names),
+        }
+    }
+
+    /// Decodes the arguments of the USDT probe.
+    /// All probe sites share one BPF program, so arguments are available only if their
+    /// locations are the same in all sites.
+    fn usdt_args(
+        locations: &Result<ProbeLocations, LocationError>,
+    ) -> Result<Vec<UsdtArg>, String> {
+        // location errors are reported on the `probe` property by the type checker
+        let points = match locations {
+            Ok(locations) => &locations.points,
+            Err(_) => return Ok(Vec::new()),
+        };
+
+        let spec = &points[0].arg_spec;
+        if points.iter().any(|tp| tp.arg_spec != *spec) {
+            return Err("USDT probe sites store arguments in different locations".to_string());
+        }
+
+        usdt_args::parse_args(spec)
+    }
+
     /// Loads UProbes from the generated program.
     pub fn load(
         &self,
         state: &mut LoadedState,
         _out_stream: MsgChannelTx,
     ) -> Result<(), RuntimeError> {
-        let prog_prop = self
-            .props
-            .get("program")
-            .ok_or(RuntimeError::ExpectedProperty("program"))?;
-
-        // TODO: use formulas evaluator
-        let prog_name = prog_prop
-            .term()
-            .ok_or_else(|| {
-                RuntimeError::Other("non-term value used for the `probe` property".to_string())
-            })?
-            .str_value()
-            .ok_or_else(|| {
-                RuntimeError::Other("expected a string value for `probe`".to_string())
-            })?;
-
-        let uprobe = state.prog.uprobe_mut("mlens").ok_or_else(|| {
-            RuntimeError::Other("expected uprobe/mlens. no compiled bpf program?".to_string())
-        })?;
-
-        let tracepoints = self.generate_trace_points()?;
+        self.attach(state, "mlens", true)
+    }
 
-        for tp in tracepoints {
-            info!("Attaching trace point to [{}]: {:?}", prog_name, tp);
+    /// Attaches a BPF program with the given name to the probe locations.
+    /// If `per_function` is set, each function matched by a pattern is attached to its own
+    /// program generated by `codegen`, e.g. `mlens_0`.
+    pub(super) fn attach(
+        &self,
+        state: &mut LoadedState,
+        bpf_prog: &str,
+        per_function: bool,
+    ) -> Result<(), RuntimeError> {
+        let locations = self
+            .locations
+            .as_ref()
+            .map_err(|e| RuntimeError::Other(e.message.clone()))?;
+        let pid = self.props.eval_num("pid")?.map(|pid| pid as i32);
+
+        for (index, tp) in locations.points.iter().enumerate() {
+            info!(
+                "Attaching trace point to [{}]: {:?}",
+                locations.binary_path, tp
+            );
+
+            let bpf_prog = if locations.per_function && per_function {
+                format!("{}_{}", bpf_prog, index)
+            } else {
+                bpf_prog.to_string()
+            };
+
+            // the attach type (entry or return) is defined by the program section
+            let uprobe = state.prog.uprobe_mut(&bpf_prog).ok_or_else(|| {
+                RuntimeError::Other(format!(
+                    "expected a {} uprobe. no compiled bpf program?",
+                    bpf_prog
+                ))
+            })?;
 
             uprobe
                 .attach_uprobe_with_semaphore(
-                    tp.fn_name.as_ref().map(|s| s.as_str()),
+                    tp.fn_name.as_deref(),
                     tp.offset,
                     tp.semaphore_offset as u32,
-                    &prog_name,
-                    None,
+                    &locations.binary_path,
+                    pid,
                 )
                 .map_err(|e| RuntimeError::Other(format!("failed to attach uprobe: {:?}", e)))?;
         }
@@ -83,86 +294,106 @@ impl UProbe {
         Ok(())
     }
 
-    fn generate_trace_points(&self) -> Result<Vec<UProbePoint>, RuntimeError> {
-        let prog_prop = self
-            .props
-            .get("program")
-            .ok_or(RuntimeError::ExpectedProperty("program"))?;
-
-        // TODO: use formulas evaluator
-        let prog_name = prog_prop
-            .term()
-            .ok_or_else(|| {
-                RuntimeError::Other("non-term value used for the `program` property".to_string())
-            })?
-            .str_value()
-            .ok_or_else(|| {
-                RuntimeError::Other("expected a string value for `program`".to_string())
-            })?;
+    /// Returns the path of the probed binary: either the `program`,
+    /// or the `library` resolved in the process `pid` or in the ld.so cache.
+    fn binary_path(props: &NodeProperties) -> Result<String, RuntimeError> {
+        match props.eval_str("library")? {
+            Some(library) => shared_libs::resolve_library(&library, props.eval_num("pid")?)
+                .map_err(RuntimeError::Other),
+            None => props
+                .eval_str("program")?
+                .ok_or(RuntimeError::ExpectedProperty("program")),
+        }
+    }
+
+    /// Finds the sites of a USDT probe in the binary.
+    fn usdt_points(binary_path: &str, probe_name: &str) -> Result<Vec<UProbePoint>, RuntimeError> {
+        let bin_data = symbols::read_binary(binary_path).map_err(RuntimeError::Other)?;
+        fn usdt_error(e: impl std::fmt::Debug) -> RuntimeError {
+            RuntimeError::Other(format!("failed to read USDT probes: {:?}", e))
+        }
 
-        if let Some(probe_formula) = self.props.get("probe") {
-            // TODO: use formulas evaluator
-            let probe_name = probe_formula
-                .term()
-                .ok_or_else(|| {
-                    RuntimeError::Other("non-term value used for the `probe` property".to_string())
-                })?
-                .str_value()
-                .ok_or_else(|| {
-                    RuntimeError::Other("expected a string value for `probe`".to_string())
-                })?;
-
-            let bin_data = std::fs::read(prog_name)?;
-            let context = UsdtContext::new(&bin_data)
-                .map_err(|e| RuntimeError::Other(format!("usdt reader error: {:?}", e)))?;
-
-            let uprobes = context
-                .probes()
-                .map_err(|e| RuntimeError::Other(format!("usdt reader error: {:?}", e)))?
-                .map(|probe| probe.unwrap()) // FIXME: proper error handling
-                .filter(|probe| probe.probe_name == probe_name)
-                .map(|probe| UProbePoint {
+        let context = UsdtContext::new(&bin_data).map_err(usdt_error)?;
+
+        let mut uprobes = Vec::new();
+        for probe in context.probes().map_err(usdt_error)? {
+            let probe = probe.map_err(usdt_error)?;
+            if probe.probe_name == probe_name {
+                uprobes.push(UProbePoint {
                     fn_name: None,
                     offset: probe.sh_addr,
                     semaphore_offset: probe.semaphore_offset,
-                })
-                .collect::<Vec<_>>();
-
-            if uprobes.is_empty() {
-                return Err(RuntimeError::Other(format!(
-                    "USDT probe {} not found",
-                    probe_name
-                )));
+                    arg_spec: probe.args.to_string(),
+                });
             }
+        }
 
-            info!("Attaching to static probe {}", probe_name);
+        if uprobes.is_empty() {
+            return Err(RuntimeError::Other(format!(
+                "USDT probe {} not found",
+                probe_name
+            )));
+        }
 
-            Ok(uprobes)
-        } else {
-            let func_prop = self
+        Ok(uprobes)
+    }
+
+    /// Checks that the probe location properties are strings.
+    pub fn typecheck(&self, _inputs: &[&Node]) -> Vec<Diagnostic> {
+        let mut errors = self.props.check_prop_types(
+            self.id,
+            &["program", "library", "function", "probe"],
+            ExprType::String,
+        );
+        errors.append(
+            &mut self
                 .props
-                .get("function")
-                .ok_or(RuntimeError::ExpectedProperty("function"))?;
-
-            // TODO: use formulas evaluator
-            let func_name = func_prop
-                .term()
-                .ok_or_else(|| {
-                    RuntimeError::Other(
-                        "non-term value used for the `function` property".to_string(),
-                    )
-                })?
-                .str_value()
-                .ok_or_else(|| {
-                    RuntimeError::Other("expected a string value for `function`".to_string())
-                })?;
-
-            Ok(vec![UProbePoint {
-                offset: 0,
-                semaphore_offset: 0,
-                fn_name: Some(func_name.to_owned()),
-            }])
+                .check_prop_types(self.id, &["pid"], ExprType::Number),
+        );
+
+        if let (Some(_), Some(formula)) = (self.props.get("program"), self.props.get("library")) {
+            errors.push(
+                Diagnostic::new(
+                    self.id,
+                    "library",
+                    formula.span(),
+                    "`program` and `library` can't be used together".to_string(),
+                )
+                .with_hint("remove one of them"),
+            );
         }
+
+        // properties of a wrong type have been reported already
+        if errors.is_empty() {
+            if let Err(error) = &self.locations {
+                if let Some(formula) = self.props.get(error.property) {
+                    errors.push(Diagnostic::new(
+                        self.id,
+                        error.property,
+                        formula.span(),
+                        error.message.clone(),
+                    ));
+                }
+            }
+        }
+
+        if self.is_return {
+            errors.append(&mut pt_regs::check_retval_size(self.id, &self.props));
+        }
+
+        if let Some(formula) = self.props.get("probe").filter(|_| self.is_return) {
+            errors.push(
+                Diagnostic::new(
+                    self.id,
+                    "probe",
+                    formula.span(),
+                    "return probes can't be attached to USDT probes".to_string(),
+                )
+                .with_hint("use `function` instead"),
+            );
+        }
+
+        errors
     }
 
     /// Generates LLVM IR from Metalens AST
@@ -172,9 +403,63 @@ impl UProbe {
         // - uprobe type used in my property
 
         // ctx.builder
+        let kind = if self.is_return {
+            "uretprobe"
+        } else {
+            "uprobe"
+        };
+        ctx.func.set_section(&format!("{}/mlens", kind));
+
+        match &self.locations {
+            Ok(locations) if locations.per_function => {
+                self.codegen_function_programs(ctx, kind, locations.points.len());
+            }
+            _ => {}
+        }
         Ok(())
     }
 
+    /// Generates a program for each matched function, which calls the main program
+    /// with the function index. The main program is inlined into them afterwards.
+    fn codegen_function_programs(&self, ctx: &mut CodegenCtx, kind: &str, count: usize) {
+        let i64_ty = ctx.llvm_context.i64_type();
+
+        for index in 0..count {
+            let func = ctx
+                .module
+                .add_function(&format!("bpf_{}", index), bpf_prog_type(ctx), None);
+            func.set_section(&format!("{}/mlens_{}", kind, index));
+
+            let entry_block = ctx.llvm_context.append_basic_block(func, "entry");
+            ctx.builder.position_at_end(entry_block);
+
+            let bpf_ctx = func
+                .get_first_param()
+                .expect("expected a context parameter");
+            let ret = ctx
+                .builder
+                .build_call(
+                    ctx.func,
+                    &[bpf_ctx.into(), i64_ty.const_int(index as u64, false).into()],
+                    "ret",
+                )
+                .try_as_basic_value()
+                .left()
+                .expect("expected a return value");
+            ctx.builder.build_return(Some(&ret));
+        }
+
+        // the main program is not loaded on its own
+        ctx.func.set_linkage(Linkage::Internal);
+        let always_inline = Attribute::get_named_enum_kind_id("alwaysinline");
+        ctx.func.add_attribute(
+            AttributeLoc::Function,
+            ctx.llvm_context.create_enum_attribute(always_inline, 0),
+        );
+
+        ctx.builder.position_at_end(ctx.current_block);
+    }
+
     /// Returns the node output type.
     pub fn output_type(&self) -> Rc<OutputType> {
         // = same output type as my input
@@ -182,10 +467,173 @@ impl UProbe {
     }
 }
 
+/// Name of the probed function available as `input.function`.
 #[derive(Clone)]
-pub struct UProbeResult {}
+enum ProbeFunction {
+    /// USDT probes are not bound to functions.
+    None,
+    Exact(String),
+    /// One of the functions matched by a pattern; the index of the called function
+    /// is passed to the main program by the program attached to it.
+    Matched(Vec<String>),
+}
+
+/// Arguments available to a probe.
+#[derive(Clone)]
+enum ProbeArgs {
+    /// Function arguments passed in registers.
+    Registers,
+    /// Syscall arguments in the user `struct pt_regs` passed to a kernel syscall wrapper.
+    SyscallRegisters,
+    /// USDT probe arguments decoded from the ELF notes, or the reason they couldn't be decoded.
+    Usdt(Result<Vec<UsdtArg>, String>),
+    /// Return probes can't read arguments:
+    /// argument registers may be overwritten by the time the function returns.
+    None,
+}
+
+#[derive(Clone)]
+pub struct UProbeResult {
+    /// Return probes can read the return value.
+    is_return: bool,
+    /// Size of the return value in bytes.
+    retval_size: u32,
+    args: ProbeArgs,
+    function: ProbeFunction,
+}
 
 impl UProbeResult {
+    pub fn new(is_return: bool) -> Self {
+        Self {
+            is_return,
+            retval_size: pt_regs::DEFAULT_RETVAL_SIZE,
+            args: if is_return {
+                ProbeArgs::None
+            } else {
+                ProbeArgs::Registers
+            },
+            function: ProbeFunction::None,
+        }
+    }
+
+    /// Sets the return value size from the `retval_size` property.
+    /// An invalid size is reported by type checking, so it's ignored here.
+    pub fn with_retval_size(mut self, props: &NodeProperties) -> Self {
+        if let Ok(size) = pt_regs::retval_size(props) {
+            self.retval_size = size;
+        }
+        self
+    }
+
+    /// Reads arguments of a kernel syscall wrapper from the user registers it receives.
+    pub fn with_syscall_args(mut self) -> Self {
+        if matches!(self.args, ProbeArgs::Registers) {
+            self.args = ProbeArgs::SyscallRegisters;
+        }
+        self
+    }
+
+    /// Generates code that returns the name of the called function.
+    fn codegen_function_name<'a>(
+        &self,
+        ctx: &mut CodegenCtx<'a>,
+    ) -> Result<ExprValue<'a>, CodegenError> {
+        let names = match &self.function {
+            ProbeFunction::None => {
+                return Err(CodegenError::Other("unknown property function".to_string()))
+            }
+            ProbeFunction::Exact(name) => {
+                return Ok(ExprValue {
+                    value: generate_string_literal(ctx, name),
+                    ty: ExprType::String,
+                })
+            }
+            ProbeFunction::Matched(names) => names,
+        };
+
+        let i8_ty = ctx.llvm_context.i8_type();
+        let i64_ty = ctx.llvm_context.i64_type();
+
+        let name_bytes = names
+            .iter()
+            .map(|name| &name.as_bytes()[..name.len().min(MAX_FUNCTION_NAME_LEN)])
+            .collect::<Vec<_>>();
+        let max_len = name_bytes.iter().map(|name| name.len()).max().unwrap_or(0);
+        // keep a NUL terminator for shorter names
+        let buf_ty = i8_ty.array_type(max_len as u32 + 1);
+
+        ctx.builder.position_at_end(ctx.allocs_block);
+        let buf = ctx.builder.build_alloca(buf_ty, "function_name");
+        ctx.builder.position_at_end(ctx.current_block);
+        ctx.builder.build_store(buf, buf_ty.const_zero());
+
+        let index = ctx
+            .func
+            .get_nth_param(1)
+            .expect("expected a function index parameter")
+            .into_int_value();
+
+        // switch (index) { case i: copy the name of the function i }
+        let done_block = ctx
+            .llvm_context
+            .append_basic_block(ctx.func, "function_name_done");
+        let cases = name_bytes
+            .iter()
+            .enumerate()
+            .map(|(index, name)| {
+                let block = ctx
+                    .llvm_context
+                    .append_basic_block(ctx.func, "function_name_case");
+                ctx.builder.position_at_end(block);
+
+                let chars = name
+                    .iter()
+                    .map(|c| i8_ty.const_int(*c as u64, false))
+                    .collect::<Vec<_>>();
+                let name = i8_ty.const_array(&chars);
+                let name_ptr = ctx.builder.build_pointer_cast(
+                    buf,
+                    name.get_type().ptr_type(AddressSpace::Generic),
+                    "name_ptr",
+                );
+                ctx.builder.build_store(name_ptr, name);
+                ctx.builder.build_unconditional_branch(done_block);
+
+                (i64_ty.const_int(index as u64, false), block)
+            })
+            .collect::<Vec<_>>();
+
+        ctx.builder.position_at_end(ctx.current_block);
+        ctx.builder.build_switch(index, done_block, &cases);
+        ctx.set_current_block(done_block);
+
+        Ok(ExprValue {
+            value: buf.as_any_value_enum(),
+            ty: ExprType::String,
+        })
+    }
+
+    /// Returns the USDT argument with the given property name, e.g. `arg0`.
+    fn usdt_arg(&self, prop_name: &str) -> Option<&UsdtArg> {
+        let args = match &self.args {
+            ProbeArgs::Usdt(Ok(args)) => args,
+            _ => return None,
+        };
+        let index = prop_name.strip_prefix("arg")?;
+        args.iter()
+            .enumerate()
+            .find(|(i, _)| i.to_string() == index)
+            .map(|(_, arg)| arg)
+    }
+
+    /// Returns the register argument index of a property, e.g. 0 for `arg0`.
+    fn reg_arg_index(&self, prop_name: &str) -> Option<usize> {
+        match self.args {
+            ProbeArgs::Registers | ProbeArgs::SyscallRegisters => pt_regs::arg_index(prop_name),
+            _ => None,
+        }
+    }
+
     pub fn codegen_proc_name<'a>(&self, ctx: &mut CodegenCtx<'a>) -> ExprValue<'a> {
         // char comm[TASK_COMM_LEN];
         // bpf_get_current_comm(&comm, sizeof(comm));
@@ -303,7 +751,51 @@ impl OutputStruct for UProbeResult {
         Ok(match prop_name {
             "process_name" => self.codegen_proc_name(ctx),
             "pid" => self.codegen_pid(ctx),
-            prop => return Err(CodegenError::Other(format!("unknown property {}", prop))),
+            "function" => self.codegen_function_name(ctx)?,
+            "retval" if self.is_return => ExprValue {
+                value: pt_regs::codegen_retval(ctx, self.retval_size).as_any_value_enum(),
+                ty: ExprType::SignedNumber,
+            },
+            prop => match (self.usdt_arg(prop), self.reg_arg_index(prop)) {
+                (Some(arg), _) => ExprValue {
+                    value: pt_regs::codegen_usdt_arg(ctx, arg).as_any_value_enum(),
+                    ty: arg.expr_type(),
+                },
+                (None, Some(index)) => {
+                    let value = match self.args {
+                        ProbeArgs::SyscallRegisters => pt_regs::codegen_syscall_arg(ctx, index),
+                        _ => pt_regs::codegen_arg(ctx, index),
+                    };
+                    ExprValue {
+                        value: value.as_any_value_enum(),
+                        ty: ExprType::Number,
+                    }
+                }
+                (None, None) => {
+                    return Err(CodegenError::Other(format!("unknown property {}", prop)))
+                }
+            },
         })
     }
+
+    fn property_type(&self, prop_name: &str) -> Option<ExprType> {
+        match prop_name {
+            "process_name" => Some(ExprType::String),
+            "pid" => Some(ExprType::Number),
+            "function" if !matches!(self.function, ProbeFunction::None) => Some(ExprType::String),
+            "retval" if self.is_return => Some(ExprType::SignedNumber),
+            prop => match (self.usdt_arg(prop), self.reg_arg_index(prop)) {
+                (Some(arg), _) => Some(arg.expr_type()),
+                (None, Some(_)) => Some(ExprType::Number),
+                (None, None) => None,
+            },
+        }
+    }
+
+    fn unavailable_reason(&self, prop_name: &str) -> Option<String> {
+        match &self.args {
+            ProbeArgs::Usdt(Err(error)) if prop_name.starts_with("arg") => Some(error.clone()),
+            _ => None,
+        }
+    }
 }
@@ -0,0 +1,150 @@
+//! Decodes USDT probe argument specs stored in ELF notes, e.g. `-4@%edi 8@-8(%rbp)`.
+//!
+//! Each argument is described as `size@location`, where a negative size means a signed value.
+//! Only the x86_64 registers are supported for now.
+
+use crate::formulas::ExprType;
+
+/// Argument of a USDT probe.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UsdtArg {
+    /// Size in bytes: 1, 2, 4, or 8.
+    pub size: u32,
+    pub signed: bool,
+    pub location: ArgLocation,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArgLocation {
+    /// Value is stored in a register, e.g. `%edi`.
+    /// Contains the index of the `struct pt_regs` field.
+    Register(u64),
+    /// Value is stored in memory at a register-relative address, e.g. `-8(%rbp)`.
+    Memory { reg: u64, offset: i64 },
+    /// Value is a constant, e.g. `$5`.
+    Const(i64),
+}
+
+impl UsdtArg {
+    pub fn expr_type(&self) -> ExprType {
+        if self.signed {
+            ExprType::SignedNumber
+        } else {
+            ExprType::Number
+        }
+    }
+}
+
+/// Parses the argument spec of a probe.
+pub fn parse_args(spec: &str) -> Result<Vec<UsdtArg>, String> {
+    spec.split_whitespace().map(parse_arg).collect()
+}
+
+fn parse_arg(spec: &str) -> Result<UsdtArg, String> {
+    let invalid = || format!("unsupported USDT argument `{}`", spec);
+
+    let (size, location) = spec.split_once('@').ok_or_else(invalid)?;
+    let size: i32 = size.parse().map_err(|_| invalid())?;
+    if !matches!(size.unsigned_abs(), 1 | 2 | 4 | 8) {
+        return Err(invalid());
+    }
+
+    let location = if let Some(value) = location.strip_prefix('$') {
+        ArgLocation::Const(value.parse().map_err(|_| invalid())?)
+    } else if let Some(reg) = location.strip_prefix('%') {
+        ArgLocation::Register(reg_index(reg).ok_or_else(invalid)?)
+    } else {
+        // symbolic offsets such as `counter(%rip)` are not supported
+        let (offset, reg) = location
+            .strip_suffix(')')
+            .and_then(|location| location.split_once("(%"))
+            .ok_or_else(invalid)?;
+
+        ArgLocation::Memory {
+            reg: reg_index(reg).ok_or_else(invalid)?,
+            offset: match offset {
+                "" => 0,
+                offset => offset.parse().map_err(|_| invalid())?,
+            },
+        }
+    };
+
+    Ok(UsdtArg {
+        size: size.unsigned_abs(),
+        signed: size < 0,
+        location,
+    })
+}
+
+/// Returns the index of a `struct pt_regs` field that holds a register,
+/// including its lower parts, e.g. `eax`, `ax`, and `al` for `rax`.
+fn reg_index(name: &str) -> Option<u64> {
+    // r8 .. r15 and their lower parts, e.g. `r8d`
+    if let Some(num) = name
+        .strip_prefix('r')
+        .filter(|num| num.starts_with(|c: char| c.is_ascii_digit()))
+    {
+        return match num.trim_end_matches(['d', 'w', 'b']) {
+            "8" => Some(9),
+            "9" => Some(8),
+            "10" => Some(7),
+            "11" => Some(6),
+            "12" => Some(3),
+            "13" => Some(2),
+            "14" => Some(1),
+            "15" => Some(0),
+            _ => None,
+        };
+    }
+
+    let base = match name.len() {
+        3 if name.starts_with(['r', 'e']) => &name[1..],
+        3 if name.ends_with('l') => &name[..2],
+        _ => name,
+    };
+
+    match base {
+        "ax" | "al" => Some(10),
+        "bx" | "bl" => Some(5),
+        "cx" | "cl" => Some(11),
+        "dx" | "dl" => Some(12),
+        "si" => Some(13),
+        "di" => Some(14),
+        "bp" => Some(4),
+        "sp" => Some(19),
+        "ip" => Some(16),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_args() {
+        let args = parse_args("-4@%edi 8@%rsi 2@-8(%rbp) -8@$-5 1@%r9b 4@(%rax)").unwrap();
+
+        assert_eq!(
+            args.iter().map(|arg| arg.location).collect::<Vec<_>>(),
+            [
+                ArgLocation::Register(14),
+                ArgLocation::Register(13),
+                ArgLocation::Memory { reg: 4, offset: -8 },
+                ArgLocation::Const(-5),
+                ArgLocation::Register(8),
+                ArgLocation::Memory { reg: 10, offset: 0 },
+            ]
+        );
+        assert_eq!(
+            (args[0].size, args[0].expr_type()),
+            (4, ExprType::SignedNumber)
+        );
+        assert_eq!((args[2].size, args[2].expr_type()), (2, ExprType::Number));
+
+        assert!(parse_args("").unwrap().is_empty());
+        assert!(parse_args("8@counter(%rip)").is_err());
+        assert!(parse_args("3@%eax").is_err());
+        assert!(parse_args("4@%xmm0").is_err());
+    }
+}
@@ -10,7 +10,7 @@ use redbpf::load::{Loaded, Loader, LoaderError};
 use tokio::time;
 use tracing::info;
 
-use crate::{ws::MsgChannelTx, ProgGraph};
+use crate::{codegen::SetMap, ws::MsgChannelTx, ProgGraph};
 
 pub type EventDeserializer = fn(Box<[u8]>) -> serde_json::Value;
 
@@ -66,10 +66,15 @@ impl Drop for LoadedState {
 pub fn load_bpf_prog(
     nodes: &ProgGraph,
     bpf_prog: &[u8],
+    set_maps: &[SetMap],
     out_stream: MsgChannelTx,
 ) -> Result<LoadedState, RuntimeError> {
     let mut prog_state = LoadedState::new(Loader::load(bpf_prog).map_err(RuntimeError::LoadError)?);
 
+    for set_map in set_maps {
+        populate_set_map(&prog_state, set_map)?;
+    }
+
     let mut exec_order = toposort(&nodes, None)
         .map_err(|_| RuntimeError::ProgContainsCycles)?
         .into_iter();
@@ -82,6 +87,23 @@ pub fn load_bpf_prog(
     Ok(prog_state)
 }
 
+/// Inserts values used in set membership checks into a BPF hash map.
+fn populate_set_map(prog_state: &LoadedState, set_map: &SetMap) -> Result<(), RuntimeError> {
+    let map = prog_state
+        .prog
+        .map(&set_map.name)
+        .ok_or_else(|| RuntimeError::Other(format!("set map {} not found", set_map.name)))?;
+
+    let hash_map = redbpf::HashMap::<u64, u8>::new(map)
+        .map_err(|e| RuntimeError::Other(format!("failed to open set map: {:?}", e)))?;
+
+    for value in &set_map.values {
+        hash_map.set(*value, 1);
+    }
+
+    Ok(())
+}
+
 pub async fn terminal_runtime(mut prog_state: LoadedState) {
     let counters_map = prog_state.prog.map("counters").unwrap().clone();
 
@@ -8,8 +8,9 @@ use tokio_tungstenite::tungstenite::{Message as WsMessage, Result as WsResult};
 use tracing::{info, warn};
 
 use crate::codegen::CodegenError;
-use crate::runtime;
+use crate::formulas::{Diagnostic, FormulaError};
 use crate::{codegen::CodegenResult, dsl::Elem, ProgGraph};
+use crate::{nodes, runtime};
 
 pub type MsgChannelTx = UnboundedSender<Message>;
 
@@ -59,19 +60,28 @@ async fn accept_connection(stream: TcpStream) -> WsResult<()> {
 
             match msg.action.as_str() {
                 "compile" => {
-                    let res = compile(&msg.payload);
+                    // probe nodes read the probed binaries, and the graph is not `Send`,
+                    // so it's compiled on this thread while other tasks are moved away from it
+                    let res = tokio::task::block_in_place(|| compile(&msg.payload));
                     match res {
                         Ok((codegen, prog_graph)) => {
                             dbg!("codegen successful");
 
+                            // clear diagnostics from previous compilations
+                            send_diagnostics(&tx2, &[]);
+
                             {
                                 // drop previously loaded prog before attaching a new one
                                 let _ = loaded_prog.take();
                             }
 
                             // execute the prog
-                            let prog =
-                                runtime::load_bpf_prog(&prog_graph, &codegen.bpf, tx2.clone());
+                            let prog = runtime::load_bpf_prog(
+                                &prog_graph,
+                                &codegen.bpf,
+                                &codegen.set_maps,
+                                tx2.clone(),
+                            );
 
                             if let Err(e) = prog {
                                 warn!("failed to execute a prog: {:?}", e);
@@ -91,11 +101,16 @@ async fn accept_connection(stream: TcpStream) -> WsResult<()> {
                                 .expect("failed to send msg");
                             });
                         }
+                        Err(CodegenError::FormulaError(FormulaError::Diagnostics(diagnostics))) => {
+                            send_diagnostics(&tx2, &diagnostics);
+                        }
                         Err(e) => {
                             warn!("compilation failed: {:?}", e);
                         }
                     }
                 }
+                // reads any path on the host, see `send_probes`
+                "list_probes" => send_probes(&tx2, msg.payload).await,
                 _ => {
                     warn!("Unknown action; message: {:?}", msg);
                 }
@@ -117,6 +132,59 @@ async fn accept_connection(stream: TcpStream) -> WsResult<()> {
     Ok(())
 }
 
+/// Sends formula errors to be displayed next to the node properties.
+fn send_diagnostics(out_stream: &MsgChannelTx, diagnostics: &[Diagnostic]) {
+    if let Err(e) = out_stream.unbounded_send(Message {
+        action: "diagnostics".to_owned(),
+        payload: serde_json::to_string(diagnostics).expect("failed to construct json"),
+    }) {
+        warn!("failed to send a message: {:?}", e);
+    }
+}
+
+/// Error encountered while listing probes of a binary.
+#[derive(Serialize)]
+struct ProbesError<'a> {
+    path: &'a str,
+    message: String,
+}
+
+/// Sends functions and USDT probes of a binary at `path`, for autocompletion in the node editor.
+///
+/// Any WebSocket client can request this for an arbitrary host path, and the server listens
+/// on 0.0.0.0, so anyone who can reach the port can probe which files exist and read symbols
+/// of any regular file up to 512 MiB that the server can open.
+async fn send_probes(out_stream: &MsgChannelTx, path: String) {
+    // reading and parsing a binary can take a while, so it's done off the async workers
+    let probes = {
+        let path = path.clone();
+        tokio::task::spawn_blocking(move || nodes::list_probes(&path)).await
+    };
+
+    let message = match probes {
+        Ok(Ok(probes)) => Message {
+            action: "probes".to_owned(),
+            payload: serde_json::to_string(&probes).expect("failed to construct json"),
+        },
+        Ok(Err(message)) => Message {
+            action: "probes_error".to_owned(),
+            payload: serde_json::to_string(&ProbesError {
+                path: &path,
+                message,
+            })
+            .expect("failed to construct json"),
+        },
+        Err(e) => {
+            warn!("failed to list probes of {}: {:?}", path, e);
+            return;
+        }
+    };
+
+    if let Err(e) = out_stream.unbounded_send(message) {
+        warn!("failed to send a message: {:?}", e);
+    }
+}
+
 fn compile(prog: &str) -> Result<(CodegenResult, ProgGraph), CodegenError> {
     let nodes: Vec<Elem> = serde_json::from_str(prog).expect("could not parse the program");
     let prog = crate::dsl::construct_prog(nodes)?;